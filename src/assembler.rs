@@ -0,0 +1,523 @@
+// A two pass assembler for the mnemonics `Instruction` prints, so the output
+// of the disassembler can be fed back into it.
+//
+//     ; Comments run to the end of the line
+//     SPEED equ 2              ; a constant
+//     start:                   ; a label, it can share its line with a statement
+//         LD V0, SPEED
+//         LD I, sprite
+//         DRW V0, V1, 4
+//         JP start
+//     org 0x300                ; continue at 0x300, padding with zeroes
+//     sprite:
+//         db 0xF0, 0x90, 0b11110000, 144
+//         dw 0xF090, start     ; big endian words
+//
+// Mnemonics and register names are case insensitive, labels and constants are
+// not. Numbers are decimal, or hexadecimal and binary with a 0x or 0b prefix,
+// and operands can add and subtract numbers, labels and constants.
+//
+// A ROM has to fit in the 4 KB of CHIP-8 memory, unless an `xochip` line says
+// it is meant for the 64 KB of XO-CHIP.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::{AsmError, Instruction, CHIP8_RAM, START_PC, XOCHIP_RAM};
+
+// An assembled ROM, to be loaded at `START_PC`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Assembly {
+    pub rom: Vec<u8>,
+    // The (source line, address) of every instruction, lines counted from 1
+    pub source_map: Vec<(usize, usize)>,
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, AsmError> {
+    let mut assembler = Assembler { symbols: BTreeMap::new() };
+    let statements = assembler.first_pass(source)?;
+
+    assembler.second_pass(&statements)
+}
+
+struct Statement<'a> {
+    line: usize,
+    addr: usize,
+    mnemonic: String,
+    operands: Vec<&'a str>,
+}
+
+struct Assembler {
+    symbols: BTreeMap<String, i64>,
+}
+
+impl Assembler {
+    // Gives every label its address and every constant its value
+    fn first_pass<'a>(&mut self, source: &'a str) -> Result<Vec<Statement<'a>>, AsmError> {
+        let mut statements = Vec::new();
+        let mut addr = START_PC;
+        let mut xochip = false;
+        // The first line that goes past the 4 KB of CHIP-8 memory
+        let mut overflow = None;
+
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let mut text = text.split(';').next().unwrap_or("").trim();
+
+            if let Some(colon) = text.find(':') {
+                let label = text[..colon].trim();
+                if is_identifier(label) {
+                    self.define(label, addr as i64, line)?;
+                    text = text[(colon + 1)..].trim();
+                }
+            }
+            if text.is_empty() {
+                continue;
+            }
+
+            let (mnemonic, rest) = match text.find(char::is_whitespace) {
+                Some(space) => (&text[..space], text[space..].trim()),
+                None => (text, ""),
+            };
+
+            let mut words = rest.splitn(2, char::is_whitespace);
+            if words.next().map(|word| word.eq_ignore_ascii_case("equ")) == Some(true) {
+                let value = self.eval(words.next().unwrap_or(""), line)?;
+                self.define(mnemonic, value, line)?;
+                continue;
+            }
+
+            let operands: Vec<&str> = if rest.is_empty() {
+                Vec::new()
+            } else {
+                rest.split(',').map(str::trim).collect()
+            };
+
+            let statement = Statement {
+                line,
+                addr,
+                mnemonic: mnemonic.to_uppercase(),
+                operands,
+            };
+
+            addr += match statement.mnemonic.as_str() {
+                "ORG" => {
+                    let target = self.single(&statement)?;
+                    let target = self.eval(target, line)?;
+                    if target >= XOCHIP_RAM as i64 {
+                        return Err(error(line, format!("org 0x{:X} is past the end of memory at 0xFFFF", target)));
+                    }
+                    if target < addr as i64 {
+                        return Err(error(line, format!("org 0x{:X} is behind 0x{:X}", target, addr)));
+                    }
+                    target as usize - addr
+                },
+                "XOCHIP" => {
+                    xochip = true;
+                    0
+                },
+                "DB" => statement.operands.len(),
+                "DW" => statement.operands.len() * 2,
+                _ if is_long_load(&statement) => 4,
+                _ => 2,
+            };
+            if addr > CHIP8_RAM && statement.mnemonic != "ORG" {
+                overflow = overflow.or(Some(line));
+            }
+            if addr > XOCHIP_RAM {
+                return Err(error(line, "the ROM runs past the end of memory at 0xFFFF".to_string()));
+            }
+
+            statements.push(statement);
+        }
+
+        match overflow {
+            Some(line) if !xochip =>
+                Err(error(line, "the ROM runs past 0xFFF, the end of CHIP-8 memory, without xochip".to_string())),
+            _ => Ok(statements),
+        }
+    }
+
+    fn second_pass(&self, statements: &[Statement]) -> Result<Assembly, AsmError> {
+        let mut rom = Vec::new();
+        let mut source_map = Vec::new();
+
+        for statement in statements {
+            let line = statement.line;
+            rom.resize(statement.addr - START_PC, 0);
+
+            match statement.mnemonic.as_str() {
+                "ORG" | "XOCHIP" => {},
+                "DB" => for operand in statement.operands.iter() {
+                    rom.push(self.byte(operand, line)?);
+                },
+                "DW" => for operand in statement.operands.iter() {
+                    rom.extend_from_slice(&self.word(operand, line)?.to_be_bytes());
+                },
+                _ => {
+                    let (instruction, long) = self.instruction(statement)?;
+                    rom.extend_from_slice(&instruction.encode().to_be_bytes());
+                    if let Some(addr) = long {
+                        rom.extend_from_slice(&addr.to_be_bytes());
+                    }
+                    source_map.push((line, statement.addr));
+                },
+            }
+        }
+
+        Ok(Assembly { rom, source_map })
+    }
+
+    fn instruction(&self, statement: &Statement) -> Result<(Instruction, Option<u16>), AsmError> {
+        let line = statement.line;
+        let ops = &statement.operands;
+        let upper: Vec<String> = ops.iter().map(|op| op.to_uppercase()).collect();
+        let special = |i: usize| upper.get(i).map(String::as_str);
+        let reg = |i: usize| self.register(ops[i], line);
+
+        let instruction = match (statement.mnemonic.as_str(), ops.len()) {
+            ("CLS", 0) => Instruction::Cls,
+            ("RET", 0) => Instruction::Ret,
+            ("SCR", 0) => Instruction::ScrollRight,
+            ("SCL", 0) => Instruction::ScrollLeft,
+            ("EXIT", 0) => Instruction::Exit,
+            ("LOW", 0) => Instruction::Low,
+            ("HIGH", 0) => Instruction::High,
+            ("AUDIO", 0) => Instruction::Audio,
+            ("SCD", 1) => Instruction::ScrollDown(self.nibble(ops[0], line)?),
+            ("SCU", 1) => Instruction::ScrollUp(self.nibble(ops[0], line)?),
+            ("PLANE", 1) => Instruction::Plane(self.nibble(ops[0], line)?),
+            ("JP", 1) => Instruction::Jump(self.addr(ops[0], line)?),
+            ("JP", 2) if special(0) == Some("V0") => Instruction::JumpV0(self.addr(ops[1], line)?),
+            ("CALL", 1) => Instruction::Call(self.addr(ops[0], line)?),
+            ("SE", 2) => match register(ops[1]) {
+                Some(y) => Instruction::SkipEqReg(reg(0)?, y),
+                None => Instruction::SkipEqByte(reg(0)?, self.byte(ops[1], line)?),
+            },
+            ("SNE", 2) => match register(ops[1]) {
+                Some(y) => Instruction::SkipNeReg(reg(0)?, y),
+                None => Instruction::SkipNeByte(reg(0)?, self.byte(ops[1], line)?),
+            },
+            ("SAVE", 2) => Instruction::SaveRange(reg(0)?, reg(1)?),
+            ("LOAD", 2) => Instruction::LoadRange(reg(0)?, reg(1)?),
+            ("LD", 2) => return self.load(statement),
+            ("ADD", 2) if special(0) == Some("I") => Instruction::AddI(reg(1)?),
+            ("ADD", 2) => match register(ops[1]) {
+                Some(y) => Instruction::AddReg(reg(0)?, y),
+                None => Instruction::AddByte(reg(0)?, self.byte(ops[1], line)?),
+            },
+            ("OR", 2) => Instruction::Or(reg(0)?, reg(1)?),
+            ("AND", 2) => Instruction::And(reg(0)?, reg(1)?),
+            ("XOR", 2) => Instruction::Xor(reg(0)?, reg(1)?),
+            ("SUB", 2) => Instruction::Sub(reg(0)?, reg(1)?),
+            ("SUBN", 2) => Instruction::Subn(reg(0)?, reg(1)?),
+            ("SHR", 1) => Instruction::Shr(reg(0)?, reg(0)?),
+            ("SHR", 2) => Instruction::Shr(reg(0)?, reg(1)?),
+            ("SHL", 1) => Instruction::Shl(reg(0)?, reg(0)?),
+            ("SHL", 2) => Instruction::Shl(reg(0)?, reg(1)?),
+            ("RND", 2) => Instruction::Random(reg(0)?, self.byte(ops[1], line)?),
+            ("DRW", 3) => Instruction::Draw(reg(0)?, reg(1)?, self.nibble(ops[2], line)?),
+            ("SKP", 1) => Instruction::SkipKey(reg(0)?),
+            ("SKNP", 1) => Instruction::SkipNotKey(reg(0)?),
+            ("PITCH", 1) => Instruction::Pitch(reg(0)?),
+            _ => return Err(self.wrong_operands(statement)),
+        };
+
+        Ok((instruction, None))
+    }
+
+    // The many forms of LD
+    fn load(&self, statement: &Statement) -> Result<(Instruction, Option<u16>), AsmError> {
+        let line = statement.line;
+        let ops = &statement.operands;
+        let reg = |i: usize| self.register(ops[i], line);
+
+        let instruction = match (ops[0].to_uppercase().as_str(), ops[1].to_uppercase().as_str()) {
+            ("I", _) if is_long_load(statement) => {
+                let addr = ops[1].trim_start()[4..].trim();
+                return Ok((Instruction::LoadLongI, Some(self.word(addr, line)?)));
+            },
+            ("I", _) => Instruction::LoadI(self.addr(ops[1], line)?),
+            ("DT", _) => Instruction::SetDelay(reg(1)?),
+            ("ST", _) => Instruction::SetSound(reg(1)?),
+            ("F", _) => Instruction::LoadFont(reg(1)?),
+            ("HF", _) => Instruction::LoadBigFont(reg(1)?),
+            ("B", _) => Instruction::Bcd(reg(1)?),
+            ("[I]", _) => Instruction::Store(reg(1)?),
+            ("R", _) => Instruction::StoreFlags(reg(1)?),
+            (_, "DT") => Instruction::LoadDelay(reg(0)?),
+            (_, "K") => Instruction::WaitKey(reg(0)?),
+            (_, "[I]") => Instruction::Load(reg(0)?),
+            (_, "R") => Instruction::LoadFlags(reg(0)?),
+            _ => match register(ops[1]) {
+                Some(y) => Instruction::LoadReg(reg(0)?, y),
+                None => Instruction::LoadByte(reg(0)?, self.byte(ops[1], line)?),
+            },
+        };
+
+        Ok((instruction, None))
+    }
+
+    fn wrong_operands(&self, statement: &Statement) -> AsmError {
+        const MNEMONICS: [&str; 31] = [
+            "CLS", "RET", "SCR", "SCL", "EXIT", "LOW", "HIGH", "AUDIO", "SCD", "SCU",
+            "PLANE", "JP", "CALL", "SE", "SNE", "SAVE", "LOAD", "LD", "ADD", "OR",
+            "AND", "XOR", "SUB", "SUBN", "SHR", "SHL", "RND", "DRW", "SKP", "SKNP",
+            "PITCH",
+        ];
+
+        if MNEMONICS.contains(&statement.mnemonic.as_str()) {
+            error(statement.line, format!("wrong operands for {}", statement.mnemonic))
+        } else {
+            error(statement.line, format!("unknown instruction {}", statement.mnemonic))
+        }
+    }
+
+    fn define(&mut self, name: &str, value: i64, line: usize) -> Result<(), AsmError> {
+        if !is_identifier(name) || register(name).is_some() {
+            return Err(error(line, format!("invalid name {}", name)));
+        }
+        if self.symbols.insert(name.to_string(), value).is_some() {
+            return Err(error(line, format!("{} is defined twice", name)));
+        }
+
+        Ok(())
+    }
+
+    fn single<'a>(&self, statement: &Statement<'a>) -> Result<&'a str, AsmError> {
+        match statement.operands[..] {
+            [operand] => Ok(operand),
+            _ => Err(error(statement.line, format!("{} takes one operand", statement.mnemonic))),
+        }
+    }
+
+    // Sums up the numbers, labels and constants of an operand
+    fn eval(&self, expr: &str, line: usize) -> Result<i64, AsmError> {
+        let mut total: i64 = 0;
+        let mut sign: i64 = 1;
+        let mut term = String::new();
+
+        // The trailing '+' closes the last term
+        for c in expr.chars().chain(Some('+')) {
+            if c != '+' && c != '-' {
+                term.push(c);
+                continue;
+            }
+
+            let name = term.trim();
+            if name.is_empty() {
+                if c == '-' && total == 0 && sign == 1 {
+                    sign = -1;
+                    continue;
+                }
+                return Err(error(line, format!("missing value in '{}'", expr.trim())));
+            }
+            total = sign.checked_mul(self.value(name, line)?)
+                .and_then(|value| total.checked_add(value))
+                .ok_or_else(|| error(line, format!("'{}' overflows", expr.trim())))?;
+            sign = if c == '+' { 1 } else { -1 };
+            term.clear();
+        }
+
+        Ok(total)
+    }
+
+    fn value(&self, term: &str, line: usize) -> Result<i64, AsmError> {
+        let lower = term.to_lowercase();
+        let number = if let Some(hex) = lower.strip_prefix("0x") {
+            i64::from_str_radix(hex, 16).ok()
+        } else if let Some(binary) = lower.strip_prefix("0b") {
+            i64::from_str_radix(binary, 2).ok()
+        } else if lower.starts_with(|c: char| c.is_ascii_digit()) {
+            lower.parse().ok()
+        } else {
+            None
+        };
+
+        match number {
+            Some(number) => Ok(number),
+            None if is_identifier(term) => match self.symbols.get(term) {
+                Some(&value) => Ok(value),
+                None => Err(error(line, format!("unknown name {}", term))),
+            },
+            None => Err(error(line, format!("invalid number {}", term))),
+        }
+    }
+
+    fn ranged(&self, expr: &str, max: i64, line: usize) -> Result<i64, AsmError> {
+        let value = self.eval(expr, line)?;
+
+        if value < 0 || value > max {
+            Err(error(line, format!("{} doesn't fit in 0..0x{:X}", value, max)))
+        } else {
+            Ok(value)
+        }
+    }
+
+    fn nibble(&self, expr: &str, line: usize) -> Result<u8, AsmError> {
+        Ok(self.ranged(expr, 0xF, line)? as u8)
+    }
+
+    fn byte(&self, expr: &str, line: usize) -> Result<u8, AsmError> {
+        Ok(self.ranged(expr, 0xFF, line)? as u8)
+    }
+
+    fn addr(&self, expr: &str, line: usize) -> Result<u16, AsmError> {
+        Ok(self.ranged(expr, 0xFFF, line)? as u16)
+    }
+
+    fn word(&self, expr: &str, line: usize) -> Result<u16, AsmError> {
+        Ok(self.ranged(expr, 0xFFFF, line)? as u16)
+    }
+
+    fn register(&self, operand: &str, line: usize) -> Result<u8, AsmError> {
+        register(operand).ok_or_else(|| error(line, format!("expected a register, found '{}'", operand)))
+    }
+}
+
+fn error(line: usize, message: String) -> AsmError {
+    AsmError { line, message }
+}
+
+// V0 to VF
+fn register(operand: &str) -> Option<u8> {
+    let mut chars = operand.chars();
+
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) =>
+            digit.to_digit(16).map(|digit| digit as u8),
+        _ => None,
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' =>
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        _ => false,
+    }
+}
+
+// LD I, long NNNN is the only 4 byte instruction
+fn is_long_load(statement: &Statement) -> bool {
+    statement.mnemonic == "LD"
+        && statement.operands.len() == 2
+        && statement.operands[0].eq_ignore_ascii_case("I")
+        && statement.operands[1].split_whitespace().next().map(|word| word.eq_ignore_ascii_case("long")) == Some(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassemble;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            ; Draws a sprite
+            SPEED equ 2
+            start:  LD V0, SPEED + 1
+                    ld i, sprite
+                    DRW V0, V1, 4
+                    LD I, long end
+                    JP start
+            org 0x210
+            sprite:
+                    db 0xF0, 0x90, 0b11110000, 144
+            end:    dw 0xABCD, start
+        ";
+
+        let assembly = assemble(source).unwrap();
+
+        assert_eq!(assembly.rom, vec![
+            0x60, 0x03,
+            0xA2, 0x10,
+            0xD0, 0x14,
+            0xF0, 0x00, 0x02, 0x14,
+            0x12, 0x00,
+            0x00, 0x00, 0x00, 0x00,
+            0xF0, 0x90, 0xF0, 0x90,
+            0xAB, 0xCD, 0x02, 0x00,
+        ]);
+        assert_eq!(assembly.source_map, vec![
+            (4, 0x200), (5, 0x202), (6, 0x204), (7, 0x206), (8, 0x20A),
+        ]);
+    }
+
+    #[test]
+    fn test_operand_forms() {
+        let source = "
+            SHR V3
+            SHL V3, V4
+            SE V1, V2
+            SE V1, 7
+            ADD I, VA
+            ADD V1, V2
+            ADD V1, 0x10
+            LD V1, [I]
+            LD [I], VF
+            JP V0, 0x300
+        ";
+
+        assert_eq!(assemble(source).unwrap().rom, vec![
+            0x83, 0x36, 0x83, 0x4E, 0x51, 0x20, 0x31, 0x07, 0xFA, 0x1E,
+            0x81, 0x24, 0x71, 0x10, 0xF1, 0x65, 0xFF, 0x55, 0xB3, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        let mut rom = Vec::new();
+        for opcode in (0..=0xFFFFu32).step_by(7) {
+            let opcode = opcode as u16;
+            if let Some(instruction) = Instruction::decode(opcode) {
+                if instruction != Instruction::LoadLongI {
+                    rom.extend_from_slice(&opcode.to_be_bytes());
+                }
+            }
+        }
+        rom.extend_from_slice(&[0xF0, 0x00, 0x12, 0x34, 0xFF, 0xFF, 0x42]);
+
+        let source: Vec<String> = disassemble(&rom, START_PC)
+            .iter()
+            .map(|line| line.to_string()[17..].to_string())
+            .collect();
+
+        // Every opcode takes more than the 4 KB of CHIP-8 memory
+        assert_eq!(assemble(&format!("xochip\n{}", source.join("\n"))).unwrap().rom, rom);
+    }
+
+    #[test]
+    fn test_xochip_memory() {
+        let assembly = assemble("CLS\norg 0x2000\ndb 7\nxochip").unwrap();
+
+        assert_eq!(assembly.rom.len(), 0x2000 - START_PC + 1);
+        assert_eq!(assembly.rom.last(), Some(&7));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source: &str| assemble(source).unwrap_err();
+
+        assert_eq!(error("CLS\nFOO V1"),
+                   AsmError { line: 2, message: "unknown instruction FOO".to_string() });
+        assert_eq!(error("\n\nDRW V0, V1").message, "wrong operands for DRW");
+        assert_eq!(error("LD V0, 256").message, "256 doesn't fit in 0..0xFF");
+        assert_eq!(error("JP nowhere").message, "unknown name nowhere");
+        assert_eq!(error("LD VG, 1").message, "expected a register, found 'VG'");
+        assert_eq!(error("a:\na:").line, 2);
+        assert_eq!(error("CLS\norg 0x100").message, "org 0x100 is behind 0x202");
+        assert_eq!(error("db 1 +").message, "missing value in '1 +'");
+        assert_eq!(error("org 0x10000").message, "org 0x10000 is past the end of memory at 0xFFFF");
+        assert_eq!(error("CLS\norg 0xFFE\nCLS\ndb 1"), AsmError {
+            line: 4,
+            message: "the ROM runs past 0xFFF, the end of CHIP-8 memory, without xochip".to_string(),
+        });
+        assert_eq!(error("xochip\norg 0xFFFF\ndw 1").message, "the ROM runs past the end of memory at 0xFFFF");
+        assert_eq!(error("db 0x7FFFFFFFFFFFFFFF + 1").message, "'0x7FFFFFFFFFFFFFFF + 1' overflows");
+    }
+}
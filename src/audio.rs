@@ -1,61 +1,112 @@
-use sdl2;
 use sdl2::Sdl;
 use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 
+use chip_8::{AudioSink, FrontendError};
+
+use crate::cli::SAMPLE_RATE;
+
 pub struct Audio {
-    device: AudioDevice<SquareWave>,
+    device: AudioDevice<Tone>,
+    // The frequency of the buzzer in Hz
+    frequency: f32,
+    pattern: Option<[u8; 16]>,
+    pitch: u8,
 }
 
 impl Audio {
-    pub fn new(sdl_context: &Sdl) -> Audio {
-        let audio_subsystem = sdl_context.audio().unwrap();
+    pub fn new(sdl_context: &Sdl, frequency: f32) -> Result<Audio, String> {
+        let audio_subsystem = sdl_context.audio()?;
 
         let desired_spec = AudioSpecDesired {
-            freq: Some(44100),
+            freq: Some(SAMPLE_RATE),
             channels: Some(1),  // mono
             samples: None       // default sample size
         };
 
         let device = audio_subsystem.open_playback(None, &desired_spec, |spec| {
             // initialize the audio callback
-            SquareWave {
-                phase_inc: 440.0 / spec.freq as f32,
+            Tone {
+                freq: spec.freq as f32,
+                phase_inc: frequency / spec.freq as f32,
                 phase: 0.0,
-                volume: 0.25
+                volume: 0.25,
+                pattern: None,
             }
-        }).unwrap();
+        })?;
 
-        Audio {
-            device: device
-        }
+        Ok(Audio {
+            device,
+            frequency,
+            pattern: None,
+            pitch: 64,
+        })
     }
 
-    pub fn beep(&self, sound_timer: &u8) {
-        if *sound_timer > 0 {
+    fn beep(&self, sound_timer: u8) {
+        if sound_timer > 0 {
             self.device.resume();
         } else {
             self.device.pause();
         }
     }
+
+    // Switches between the plain buzzer and an XO-CHIP audio pattern
+    fn set_pattern(&mut self, pattern: Option<[u8; 16]>, pitch: u8) {
+        if self.pattern == pattern && self.pitch == pitch {
+            return;
+        }
+        self.pattern = pattern;
+        self.pitch = pitch;
+
+        let mut tone = self.device.lock();
+        tone.pattern = pattern;
+        tone.phase = 0.0;
+        tone.phase_inc = match pattern {
+            // The whole 128 bit pattern is one period
+            Some(_) => pattern_rate(pitch) / 128.0 / tone.freq,
+            None => self.frequency / tone.freq,
+        };
+    }
+}
+
+impl AudioSink for Audio {
+    fn play(&mut self, sound_timer: u8, pattern: Option<[u8; 16]>, pitch: u8) -> Result<(), FrontendError> {
+        self.set_pattern(pattern, pitch);
+        self.beep(sound_timer);
+
+        Ok(())
+    }
+}
+
+// XO-CHIP plays the pattern at 4000 bits per second at pitch 64, and
+// doubles that every 48 steps
+fn pattern_rate(pitch: u8) -> f32 {
+    4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0)
 }
 
-struct SquareWave {
+struct Tone {
+    freq: f32,
     phase_inc: f32,
     phase: f32,
-    volume: f32
+    volume: f32,
+    pattern: Option<[u8; 16]>,
 }
 
-impl AudioCallback for SquareWave {
+impl AudioCallback for Tone {
     type Channel = f32;
 
     fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
         for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
+            let high = match self.pattern {
+                // Play the pattern one bit at a time, most significant first
+                Some(pattern) => {
+                    let bit = (self.phase * 128.0) as usize;
+                    (pattern[bit / 8] >> (7 - bit % 8)) & 1 == 1
+                },
+                // Generate a square wave
+                None => self.phase <= 0.5,
             };
+            *x = if high { self.volume } else { -self.volume };
             self.phase = (self.phase + self.phase_inc) % 1.0;
         }
     }
@@ -0,0 +1,550 @@
+// The command line. There are few enough options that they're parsed by hand,
+// and every mistake in them is reported as a message rather than a panic.
+
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::str::FromStr;
+
+use chip_8::Quirks;
+
+pub const USAGE: &str = "\
+Usage: chip-8 [options] <rom>
+       chip-8 tty [options] <rom>
+       chip-8 headless <rom> [--frames N | --cycles N] [--keys script] [--screen file] [--quirks name]
+                       [--ips N] [--seed N] [--random name]
+       chip-8 disasm <rom>
+       chip-8 asm <source> <output>
+       chip-8 gdb <rom> [port]
+       chip-8 dap
+
+Options:
+    --ips <n>              Instructions per second, 600 by default
+    --scale <n>            Window pixels per CHIP-8 pixel, rounded up to an
+                           even number, 20 by default
+    --fg <rrggbb>          Colour of the lit pixels
+    --bg <rrggbb>          Colour of the unlit pixels
+    --quirks <name>        chip8, vip, chip48, schip or xochip, picked from
+                           the file extension by default
+    --key-on-press         FX0A takes a key as soon as it's pressed, instead
+                           of once it's released
+    --mute                 No sound
+    --tone <hz>            Pitch of the buzzer, 440 by default
+    --keymap <file>        Keypad layout, a TOML file or qwerty, numpad or
+                           cosmac, see the README
+    --vsync                Present the window in step with the refresh of
+                           the display
+    --paused               Start paused, Space resumes
+    --seed <n>             Seed of the random numbers, so that a run can be
+                           repeated
+    --random <name>        Where the random numbers come from, xorshift by
+                           default, or vip for the COSMAC VIP's routine
+    --trace <file>         Write every instruction run to the file
+    --trace-range <a-b>    Only trace the instructions between two addresses
+    -h, --help             Show this message";
+
+// 600 instructions per second
+pub const INSTRUCTIONS_PER_FRAME: usize = 10;
+const DEFAULT_SCALE: u32 = 20;
+const DEFAULT_TONE: f32 = 440.0;
+// The rate the audio device is asked for, and the buzzer has to stay under
+// half of it to be heard as the pitch it is
+pub const SAMPLE_RATE: i32 = 44100;
+// Ten seconds
+const DEFAULT_FRAMES: u64 = 600;
+
+pub type Rgb = (u8, u8, u8);
+
+// Indexed by the bitplanes a pixel is lit on, only XO-CHIP uses the last two
+pub const DEFAULT_PALETTE: [Rgb; 4] = [(0, 0, 0), (0, 250, 0), (250, 120, 0), (250, 250, 250)];
+
+// The generator behind CXKK
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Random {
+    XorShift,
+    CosmacVip,
+}
+
+pub enum Command {
+    // Plays in a window
+    Run(Options),
+    // Plays in the terminal
+    Tty(Options),
+    Headless(HeadlessOptions),
+    Disasm(String),
+    Asm { source: String, output: String },
+    Gdb { rom: String, port: Option<u16> },
+    Dap,
+    Help,
+}
+
+// How to play a ROM
+pub struct Options {
+    pub rom: String,
+    pub instructions_per_second: u32,
+    // Only the window has a scale, a tone and vsync
+    #[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+    pub scale: u32,
+    pub palette: [Rgb; 4],
+    pub quirks: Quirks,
+    pub mute: bool,
+    // The frequency of the buzzer in Hz
+    #[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+    pub tone: f32,
+    pub keymap: Option<String>,
+    #[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+    pub vsync: bool,
+    pub paused: bool,
+    pub trace: Option<String>,
+    pub trace_range: Option<RangeInclusive<usize>>,
+    pub random: Random,
+    // A seed drawn at random if there's none
+    pub seed: Option<u32>,
+}
+
+// How to run a ROM without a window
+pub struct HeadlessOptions {
+    pub rom: String,
+    // The run stops after this many frames, or else at the cycle limit
+    pub frames: Option<u64>,
+    pub cycles: Option<u64>,
+    // A key script to play
+    pub keys: Option<String>,
+    // Where to write the screen, printed out if there's nowhere
+    pub screen: Option<String>,
+    pub quirks: Quirks,
+    pub instructions_per_second: u32,
+    pub random: Random,
+    pub seed: Option<u32>,
+}
+
+impl Options {
+    pub fn instructions_per_frame(&self) -> usize {
+        per_frame(self.instructions_per_second)
+    }
+}
+
+impl HeadlessOptions {
+    pub fn instructions_per_frame(&self) -> usize {
+        per_frame(self.instructions_per_second)
+    }
+}
+
+// The timers run at 60 Hz, so the VM runs a 60th of the instructions per
+// second at a time
+fn per_frame(instructions_per_second: u32) -> usize {
+    ((instructions_per_second as f32 / 60.0).round() as usize).max(1)
+}
+
+// -h and --help ask for help wherever an option could go, but not as the
+// value of another option
+pub fn parse(args: impl Iterator<Item = String>) -> Result<Command, String> {
+    let mut args = args.peekable();
+
+    let command = match args.peek().map(String::as_str) {
+        None => return Err("No ROM given".to_string()),
+        Some("tty") | Some("headless") | Some("disasm") | Some("asm") | Some("gdb") | Some("dap") =>
+            args.next().unwrap(),
+        Some(_) => return parse_options(args).map(|options| options.map_or(Command::Help, Command::Run)),
+    };
+
+    match command.as_str() {
+        "tty" => return parse_options(args).map(|options| options.map_or(Command::Help, Command::Tty)),
+        "headless" => return parse_headless(args).map(|options| options.map_or(Command::Help, Command::Headless)),
+        _ => {},
+    }
+
+    // The other commands have no options
+    let args: Vec<String> = args.collect();
+    if args.iter().any(|arg| is_help(arg)) {
+        return Ok(Command::Help);
+    }
+    let mut args = args.into_iter();
+
+    let mut positional = |what: &str| args.next().ok_or_else(|| format!("{} needs {}", command, what));
+
+    let parsed = match command.as_str() {
+        "disasm" => Command::Disasm(positional("a ROM")?),
+        "asm" => Command::Asm { source: positional("a source file")?, output: positional("an output file")? },
+        "gdb" => {
+            let rom = positional("a ROM")?;
+            let port = match args.next() {
+                Some(port) => Some(port.parse().map_err(|_| format!("Invalid port {}", port))?),
+                None => None,
+            };
+            Command::Gdb { rom, port }
+        },
+        _ => Command::Dap,
+    };
+
+    match args.next() {
+        Some(arg) => Err(format!("Unexpected argument {}", arg)),
+        None => Ok(parsed),
+    }
+}
+
+// None if the options ask for help
+fn parse_options(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut rom = None;
+    let mut instructions_per_second = INSTRUCTIONS_PER_FRAME as u32 * 60;
+    let mut scale = DEFAULT_SCALE;
+    let mut palette = DEFAULT_PALETTE;
+    let mut quirks = None;
+    let mut key_on_press = false;
+    let mut mute = false;
+    let mut tone = DEFAULT_TONE;
+    let mut keymap = None;
+    let mut vsync = false;
+    let mut paused = false;
+    let mut trace = None;
+    let mut trace_range = None;
+    let mut random = Random::XorShift;
+    let mut seed = None;
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            if rom.is_some() {
+                return Err(format!("Unexpected argument {}", arg));
+            }
+            rom = Some(arg);
+            continue;
+        }
+
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--ips" => instructions_per_second = positive(&arg, &value()?)?,
+            "--scale" => scale = positive(&arg, &value()?)?,
+            "--fg" => palette[1] = parse_color(&value()?)?,
+            "--bg" => palette[0] = parse_color(&value()?)?,
+            "--quirks" => {
+                let name = value()?;
+                quirks = Some(Quirks::from_name(&name).ok_or_else(|| format!("Unknown quirks {}", name))?);
+            },
+            "--key-on-press" => key_on_press = true,
+            "--mute" => mute = true,
+            "--tone" => {
+                let text = value()?;
+                tone = match text.parse::<f32>() {
+                    Ok(hz) if hz > 0.0 && hz < SAMPLE_RATE as f32 / 2.0 => hz,
+                    _ => return Err(format!(
+                        "Invalid --tone {}, expected a frequency in Hz above 0 and below {}",
+                        text,
+                        SAMPLE_RATE / 2,
+                    )),
+                };
+            },
+            "--keymap" => keymap = Some(value()?),
+            "--vsync" => vsync = true,
+            "--paused" => paused = true,
+            "--trace" => trace = Some(value()?),
+            "--trace-range" => trace_range = Some(parse_range(&value()?)?),
+            "--seed" => seed = Some(number(&arg, &value()?)?),
+            "--random" => random = parse_random(&value()?)?,
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+
+    let rom = rom.ok_or_else(|| "No ROM given".to_string())?;
+    let mut quirks = quirks.unwrap_or_else(|| quirks_for(&rom));
+    quirks.key_wait_on_press |= key_on_press;
+
+    Ok(Some(Options {
+        quirks,
+        rom,
+        instructions_per_second,
+        scale,
+        palette,
+        mute,
+        tone,
+        keymap,
+        vsync,
+        paused,
+        trace,
+        trace_range,
+        random,
+        seed,
+    }))
+}
+
+fn parse_headless(mut args: impl Iterator<Item = String>) -> Result<Option<HeadlessOptions>, String> {
+    let mut rom = None;
+    let mut frames = None;
+    let mut cycles = None;
+    let mut keys = None;
+    let mut screen = None;
+    let mut quirks = None;
+    let mut instructions_per_second = INSTRUCTIONS_PER_FRAME as u32 * 60;
+    let mut random = Random::XorShift;
+    let mut seed = None;
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            if rom.is_some() {
+                return Err(format!("Unexpected argument {}", arg));
+            }
+            rom = Some(arg);
+            continue;
+        }
+
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--frames" => frames = Some(number(&arg, &value()?)?),
+            "--cycles" => cycles = Some(number(&arg, &value()?)?),
+            "--keys" => keys = Some(value()?),
+            "--screen" => screen = Some(value()?),
+            "--ips" => instructions_per_second = positive(&arg, &value()?)?,
+            "--seed" => seed = Some(number(&arg, &value()?)?),
+            "--random" => random = parse_random(&value()?)?,
+            "--quirks" => {
+                let name = value()?;
+                quirks = Some(Quirks::from_name(&name).ok_or_else(|| format!("Unknown quirks {}", name))?);
+            },
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+
+    let rom = rom.ok_or_else(|| "headless needs a ROM".to_string())?;
+    // A cycle limit alone runs for as many frames as it takes
+    let frames = match (frames, cycles) {
+        (None, None) => Some(DEFAULT_FRAMES),
+        (frames, _) => frames,
+    };
+
+    Ok(Some(HeadlessOptions {
+        quirks: quirks.unwrap_or_else(|| quirks_for(&rom)),
+        rom,
+        frames,
+        cycles,
+        keys,
+        screen,
+        instructions_per_second,
+        random,
+        seed,
+    }))
+}
+
+fn is_help(arg: &str) -> bool {
+    arg == "-h" || arg == "--help"
+}
+
+fn number<T: FromStr>(option: &str, text: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("Invalid {} {}, expected a number", option, text))
+}
+
+fn parse_random(name: &str) -> Result<Random, String> {
+    match name {
+        "xorshift" => Ok(Random::XorShift),
+        "vip" => Ok(Random::CosmacVip),
+        _ => Err(format!("Unknown random source {}, expected xorshift or vip", name)),
+    }
+}
+
+fn positive(option: &str, text: &str) -> Result<u32, String> {
+    match text.parse() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("Invalid {} {}, expected a number above 0", option, text)),
+    }
+}
+
+// RRGGBB in hexadecimal, with or without a leading '#'
+fn parse_color(text: &str) -> Result<Rgb, String> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    let channel = |i: usize| hex.get(i..(i + 2)).and_then(|digits| u8::from_str_radix(digits, 16).ok());
+
+    match (hex.len(), channel(0), channel(2), channel(4)) {
+        (6, Some(r), Some(g), Some(b)) => Ok((r, g, b)),
+        _ => Err(format!("Invalid colour {}, expected RRGGBB", text)),
+    }
+}
+
+// Picks the platform from the usual Octo file extensions
+pub fn quirks_for(filename: &str) -> Quirks {
+    match Path::new(filename).extension().and_then(|ext| ext.to_str()) {
+        Some("sc8") => Quirks::SUPER_CHIP,
+        Some("xo8") => Quirks::XO_CHIP,
+        _ => Quirks::default(),
+    }
+}
+
+// An address in hexadecimal with a leading 0x, or else in decimal
+fn parse_addr(text: &str) -> Option<usize> {
+    match text.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// <start>-<end>, both addresses included
+fn parse_range(text: &str) -> Result<RangeInclusive<usize>, String> {
+    let mut bounds = text.splitn(2, '-').map(parse_addr);
+
+    match (bounds.next().flatten(), bounds.next().flatten()) {
+        (Some(start), Some(end)) => Ok(start..=end),
+        _ => Err(format!("Invalid trace range {}, expected <start>-<end>", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_line(line: &str) -> Result<Command, String> {
+        parse(line.split_whitespace().map(str::to_string))
+    }
+
+    fn error(line: &str) -> String {
+        match parse_line(line) {
+            Ok(_) => panic!("{} should fail", line),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn test_parse_run() {
+        let options = match parse_line("--ips 1200 --fg #FF8000 --key-on-press --mute game.sc8") {
+            Ok(Command::Run(options)) => options,
+            _ => panic!("expected a run"),
+        };
+
+        assert_eq!(options.rom, "game.sc8");
+        assert_eq!(options.instructions_per_frame(), 20);
+        assert_eq!(options.scale, DEFAULT_SCALE);
+        assert_eq!(options.palette[1], (0xFF, 0x80, 0x00));
+        assert_eq!(options.palette[0], DEFAULT_PALETTE[0]);
+        assert!(options.quirks.key_wait_on_press);
+        assert!(options.quirks.clip_sprites);
+        assert!(options.mute);
+        assert!(!options.paused);
+        assert_eq!(options.trace_range, None);
+        assert_eq!(options.random, Random::XorShift);
+        assert_eq!(options.seed, None);
+    }
+
+    #[test]
+    fn test_parse_tty() {
+        let line = "tty game.ch8 --quirks xochip --trace out.txt --trace-range 0x200-0x210 --seed 5";
+        let options = match parse_line(line) {
+            Ok(Command::Tty(options)) => options,
+            _ => panic!("expected tty"),
+        };
+
+        assert_eq!(options.quirks, Quirks::XO_CHIP);
+        assert_eq!(options.trace.as_deref(), Some("out.txt"));
+        assert_eq!(options.trace_range, Some(0x200..=0x210));
+        assert_eq!(options.seed, Some(5));
+    }
+
+    #[test]
+    fn test_parse_headless() {
+        let options = match parse_line("headless game.xo8 --keys keys.txt --screen out.png") {
+            Ok(Command::Headless(options)) => options,
+            _ => panic!("expected headless"),
+        };
+        assert_eq!(options.rom, "game.xo8");
+        assert_eq!(options.frames, Some(DEFAULT_FRAMES));
+        assert_eq!(options.cycles, None);
+        assert_eq!(options.keys.as_deref(), Some("keys.txt"));
+        assert_eq!(options.screen.as_deref(), Some("out.png"));
+        assert_eq!(options.quirks, Quirks::XO_CHIP);
+        assert_eq!(options.instructions_per_frame(), INSTRUCTIONS_PER_FRAME);
+
+        // A cycle limit alone lifts the frame limit
+        match parse_line("headless game.ch8 --cycles 100 --quirks vip --random vip --seed 77") {
+            Ok(Command::Headless(options)) => {
+                assert_eq!(options.frames, None);
+                assert_eq!(options.cycles, Some(100));
+                assert_eq!(options.quirks, Quirks::COSMAC_VIP);
+                assert_eq!(options.random, Random::CosmacVip);
+                assert_eq!(options.seed, Some(77));
+            },
+            _ => panic!("expected headless"),
+        }
+        match parse_line("headless --frames 0 --cycles 100 game.ch8") {
+            Ok(Command::Headless(options)) => assert_eq!((options.frames, options.cycles), (Some(0), Some(100))),
+            _ => panic!("expected headless"),
+        }
+        match parse_line("headless game.ch8 --ips 30") {
+            Ok(Command::Headless(options)) => assert_eq!(options.instructions_per_frame(), 1),
+            _ => panic!("expected headless"),
+        }
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert!(matches!(parse_line("disasm game.ch8"), Ok(Command::Disasm(rom)) if rom == "game.ch8"));
+        assert!(matches!(parse_line("asm game.asm game.ch8"),
+                         Ok(Command::Asm { source, output }) if source == "game.asm" && output == "game.ch8"));
+        assert!(matches!(parse_line("gdb game.ch8"), Ok(Command::Gdb { port: None, .. })));
+        assert!(matches!(parse_line("gdb game.ch8 4321"), Ok(Command::Gdb { port: Some(4321), .. })));
+        assert!(matches!(parse_line("dap"), Ok(Command::Dap)));
+        assert!(matches!(parse_line("tty --mute -h"), Ok(Command::Help)));
+        assert!(matches!(parse_line("--help"), Ok(Command::Help)));
+        assert!(matches!(parse_line("headless game.ch8 -h"), Ok(Command::Help)));
+        assert!(matches!(parse_line("disasm --help"), Ok(Command::Help)));
+
+        // Values that look like -h aren't asking for help
+        match parse_line("game.ch8 --trace -h") {
+            Ok(Command::Run(options)) => assert_eq!(options.trace.as_deref(), Some("-h")),
+            _ => panic!("expected a run"),
+        }
+        match parse_line("headless game.ch8 --screen --help") {
+            Ok(Command::Headless(options)) => assert_eq!(options.screen.as_deref(), Some("--help")),
+            _ => panic!("expected headless"),
+        }
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("102030"), Ok((0x10, 0x20, 0x30)));
+        assert_eq!(parse_color("#a0B0c0"), Ok((0xA0, 0xB0, 0xC0)));
+        assert!(parse_color("#12345").is_err());
+        assert!(parse_color("1234567").is_err());
+        assert!(parse_color("12345g").is_err());
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("0x200-0x2FF"), Ok(0x200..=0x2FF));
+        assert_eq!(parse_range("512-520"), Ok(512..=520));
+        assert!(parse_range("0x200").is_err());
+        assert!(parse_range("0x200-").is_err());
+        assert!(parse_range("a-b").is_err());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error(""), "No ROM given");
+        assert_eq!(error("--mute"), "No ROM given");
+        assert_eq!(error("a.ch8 b.ch8"), "Unexpected argument b.ch8");
+        assert_eq!(error("game.ch8 --ips"), "--ips needs a value");
+        assert_eq!(error("game.ch8 --ips 0"), "Invalid --ips 0, expected a number above 0");
+        assert_eq!(error("game.ch8 --scale big"), "Invalid --scale big, expected a number above 0");
+        assert_eq!(error("game.ch8 --bg red"), "Invalid colour red, expected RRGGBB");
+        assert_eq!(error("game.ch8 --trace-range 0x200"),
+                   "Invalid trace range 0x200, expected <start>-<end>");
+        assert_eq!(error("game.ch8 --quirks chip9"), "Unknown quirks chip9");
+        assert_eq!(error("game.ch8 --tone -5"),
+                   "Invalid --tone -5, expected a frequency in Hz above 0 and below 22050");
+        assert!(error("game.ch8 --tone inf").starts_with("Invalid --tone inf"));
+        assert!(error("game.ch8 --tone NaN").starts_with("Invalid --tone NaN"));
+        assert!(error("game.ch8 --tone 22050").starts_with("Invalid --tone 22050"));
+        assert_eq!(error("game.ch8 --fullscreen"), "Unknown option --fullscreen");
+        assert_eq!(error("game.ch8 --seed -1"), "Invalid --seed -1, expected a number");
+        assert_eq!(error("game.ch8 --random lfsr"), "Unknown random source lfsr, expected xorshift or vip");
+
+        assert_eq!(error("disasm"), "disasm needs a ROM");
+        assert_eq!(error("asm game.asm"), "asm needs an output file");
+        assert_eq!(error("gdb game.ch8 port"), "Invalid port port");
+        assert_eq!(error("dap game.ch8"), "Unexpected argument game.ch8");
+
+        assert_eq!(error("headless"), "headless needs a ROM");
+        assert_eq!(error("headless game.ch8 --frames"), "--frames needs a value");
+        assert_eq!(error("headless game.ch8 --cycles many"), "Invalid --cycles many, expected a number");
+        assert_eq!(error("headless game.ch8 --quirks chip9"), "Unknown quirks chip9");
+        assert_eq!(error("headless game.ch8 --ips 0"), "Invalid --ips 0, expected a number above 0");
+        assert_eq!(error("headless game.ch8 --mute"), "Unknown option --mute");
+    }
+}
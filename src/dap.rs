@@ -0,0 +1,562 @@
+// A Debug Adapter Protocol server, so editors like VS Code can debug a ROM.
+//
+// The launch request takes the path of the `program` to run, either a ROM or
+// an assembler source ending in .asm, the latter allowing breakpoints by source
+// line. It also takes an optional `quirks` name, see `Quirks::from_name`, and
+// `stopOnEntry`. Breakpoints by address come through the
+// setInstructionBreakpoints request. Editors set breakpoints before they
+// launch, so line breakpoints are kept by path until the source is assembled,
+// and a breakpoint event tells the editor where each one landed.
+//
+// There is a single thread, and the registers are the variables of a single
+// scope. Running is synchronous: a continue request returns once the debugger
+// stops, which for a ROM stuck in a loop is after its cycle limit.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::{assemble, Chip8, Chip8Error, Debugger, Instruction, Quirks, StopReason, OPCODE_SIZE};
+
+const THREAD_ID: i64 = 1;
+const REGISTERS_REFERENCE: i64 = 1;
+
+pub struct DapServer<R, W> {
+    input: R,
+    output: W,
+    seq: i64,
+    debugger: Option<Debugger>,
+    stop_on_entry: bool,
+    // The path and the (line, address) pairs of an assembled program
+    source: Option<(String, Vec<(usize, usize)>)>,
+    // The (id, line) pairs of the breakpoints requested for each source file
+    requested_lines: BTreeMap<String, Vec<(i64, usize)>>,
+    next_breakpoint_id: i64,
+    line_breakpoints: BTreeSet<usize>,
+    instruction_breakpoints: BTreeSet<usize>,
+}
+
+impl<R: BufRead, W: Write> DapServer<R, W> {
+    pub fn new(input: R, output: W) -> DapServer<R, W> {
+        DapServer {
+            input,
+            output,
+            seq: 0,
+            debugger: None,
+            stop_on_entry: false,
+            source: None,
+            requested_lines: BTreeMap::new(),
+            next_breakpoint_id: 1,
+            line_breakpoints: BTreeSet::new(),
+            instruction_breakpoints: BTreeSet::new(),
+        }
+    }
+
+    // Answers requests until the editor disconnects
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(request) = self.read_message()? {
+            let command = request["command"].as_str().unwrap_or("").to_string();
+            let arguments = &request["arguments"];
+
+            let response = match self.handle(&command, arguments) {
+                Ok(body) => json!({
+                    "type": "response",
+                    "request_seq": request["seq"],
+                    "success": true,
+                    "command": command,
+                    "body": body,
+                }),
+                Err(message) => json!({
+                    "type": "response",
+                    "request_seq": request["seq"],
+                    "success": false,
+                    "command": command,
+                    "message": message,
+                }),
+            };
+            self.send(response)?;
+
+            match command.as_str() {
+                "initialize" => self.event("initialized", json!({}))?,
+                "launch" => {
+                    let path = self.source.as_ref().map(|(path, _)| path.clone());
+                    for (breakpoint, _) in path.map(|path| self.resolve_lines(&path)).unwrap_or_default() {
+                        self.event("breakpoint", json!({ "reason": "changed", "breakpoint": breakpoint }))?;
+                    }
+                },
+                "configurationDone" => {
+                    if self.stop_on_entry {
+                        self.stopped("entry", None)?;
+                    } else {
+                        let reason = self.with_debugger(Debugger::resume);
+                        self.report(reason)?;
+                    }
+                },
+                "continue" => {
+                    let reason = self.with_debugger(Debugger::resume);
+                    self.report(reason)?;
+                },
+                "next" => {
+                    let reason = self.with_debugger(Debugger::step_over);
+                    self.report(reason)?;
+                },
+                "stepIn" => {
+                    let reason = self.with_debugger(Debugger::step);
+                    self.report(reason)?;
+                },
+                "stepOut" => {
+                    let reason = self.with_debugger(Debugger::step_out);
+                    self.report(reason)?;
+                },
+                "pause" => self.stopped("pause", None)?,
+                "disconnect" => return Ok(()),
+                _ => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, command: &str, arguments: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsInstructionBreakpoints": true,
+            })),
+            "launch" => self.launch(arguments).map(|_| json!({})),
+            "setBreakpoints" => Ok(self.set_breakpoints(arguments)),
+            "setInstructionBreakpoints" => Ok(self.set_instruction_breakpoints(arguments)),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "CHIP-8" }] })),
+            "stackTrace" => self.chip8().map(|chip8| self.stack_trace(chip8)),
+            "scopes" => Ok(json!({
+                "scopes": [{
+                    "name": "Registers",
+                    "variablesReference": REGISTERS_REFERENCE,
+                    "expensive": false,
+                }],
+            })),
+            "variables" => self.chip8().map(variables),
+            "configurationDone" | "continue" | "next" | "stepIn" | "stepOut" | "pause" => {
+                self.chip8()?;
+                Ok(json!({}))
+            },
+            "disconnect" => Ok(json!({})),
+            _ => Err(format!("{} isn't supported", command)),
+        }
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<(), String> {
+        let program = arguments["program"].as_str().ok_or("The program to debug is missing")?;
+        let quirks = match arguments["quirks"].as_str() {
+            Some(name) => Quirks::from_name(name).ok_or(format!("Unknown quirks {}", name))?,
+            None => Quirks::default(),
+        };
+
+        let rom = if program.ends_with(".asm") {
+            let text = fs::read_to_string(program).map_err(|error| format!("{}: {}", program, error))?;
+            let assembly = assemble(&text).map_err(|error| format!("{}: {}", program, error))?;
+            self.source = Some((program.to_string(), assembly.source_map));
+            assembly.rom
+        } else {
+            fs::read(program).map_err(|error| format!("{}: {}", program, error))?
+        };
+
+        let mut chip8 = Chip8::with_quirks(quirks);
+        chip8.load(&rom);
+        self.debugger = Some(Debugger::new(chip8));
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.update_line_breakpoints();
+
+        Ok(())
+    }
+
+    // Replaces the breakpoints of a source file
+    fn set_breakpoints(&mut self, arguments: &Value) -> Value {
+        let path = arguments["source"]["path"].as_str().unwrap_or("").to_string();
+
+        let mut lines = Vec::new();
+        for requested in arguments["breakpoints"].as_array().into_iter().flatten() {
+            lines.push((self.next_breakpoint_id, requested["line"].as_u64().unwrap_or(0) as usize));
+            self.next_breakpoint_id += 1;
+        }
+        self.requested_lines.insert(path.clone(), lines);
+        self.update_line_breakpoints();
+
+        let breakpoints: Vec<Value> = self.resolve_lines(&path).into_iter().map(|(breakpoint, _)| breakpoint).collect();
+        json!({ "breakpoints": breakpoints })
+    }
+
+    // The breakpoints requested for a source file, and their addresses. Lines
+    // without an instruction move to the next line that has one, and only the
+    // launched program has any.
+    fn resolve_lines(&self, path: &str) -> Vec<(Value, Option<usize>)> {
+        let source_map = match &self.source {
+            Some((source, map)) if source == path => map.as_slice(),
+            _ => &[],
+        };
+
+        self.requested_lines.get(path).into_iter().flatten().map(|&(id, line)| {
+            match source_map.iter().filter(|&&(mapped, _)| mapped >= line).min() {
+                Some(&(line, addr)) => (json!({ "id": id, "verified": true, "line": line }), Some(addr)),
+                None => (json!({
+                    "id": id,
+                    "verified": false,
+                    "line": line,
+                    "message": "No instruction on or after this line",
+                }), None),
+            }
+        }).collect()
+    }
+
+    fn update_line_breakpoints(&mut self) {
+        let path = self.source.as_ref().map(|(path, _)| path.clone());
+        let resolved = path.map(|path| self.resolve_lines(&path)).unwrap_or_default();
+
+        self.line_breakpoints = resolved.into_iter().filter_map(|(_, addr)| addr).collect();
+        self.update_breakpoints();
+    }
+
+    // Replaces the breakpoints by address, given as "0x206" or "518"
+    fn set_instruction_breakpoints(&mut self, arguments: &Value) -> Value {
+        self.instruction_breakpoints.clear();
+        let mut breakpoints = Vec::new();
+        for requested in arguments["breakpoints"].as_array().into_iter().flatten() {
+            let reference = requested["instructionReference"].as_str().unwrap_or("");
+            let addr = match reference.strip_prefix("0x") {
+                Some(hex) => i64::from_str_radix(hex, 16).ok(),
+                None => reference.parse().ok(),
+            };
+
+            match addr.map(|addr| addr + requested["offset"].as_i64().unwrap_or(0)) {
+                Some(addr) if addr >= 0 => {
+                    self.instruction_breakpoints.insert(addr as usize);
+                    breakpoints.push(json!({ "verified": true, "instructionReference": format!("0x{:04X}", addr) }));
+                },
+                _ => breakpoints.push(json!({ "verified": false, "message": "Invalid address" })),
+            }
+        }
+        self.update_breakpoints();
+
+        json!({ "breakpoints": breakpoints })
+    }
+
+    fn update_breakpoints(&mut self) {
+        if let Some(debugger) = self.debugger.as_mut() {
+            let old: Vec<usize> = debugger.breakpoints().collect();
+            for addr in old {
+                debugger.remove_breakpoint(addr);
+            }
+            for &addr in self.line_breakpoints.union(&self.instruction_breakpoints) {
+                debugger.add_breakpoint(addr);
+            }
+        }
+    }
+
+    // The current instruction, then the CALL of every subroutine in progress
+    fn stack_trace(&self, chip8: &Chip8) -> Value {
+        let mut addrs = vec![chip8.pc()];
+        addrs.extend(chip8.stack().iter().rev().map(|&ret| (ret as usize).saturating_sub(OPCODE_SIZE)));
+
+        let frames: Vec<Value> = addrs.iter().enumerate().map(|(id, &addr)| {
+            let name = match chip8.memory().get(addr..(addr + 2)) {
+                Some(bytes) => match Instruction::decode(u16::from_be_bytes([bytes[0], bytes[1]])) {
+                    Some(instruction) => format!("0x{:04X}  {}", addr, instruction),
+                    None => format!("0x{:04X}", addr),
+                },
+                None => format!("0x{:04X}", addr),
+            };
+            let mut frame = json!({
+                "id": id,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format!("0x{:04X}", addr),
+            });
+
+            if let Some((path, map)) = &self.source {
+                if let Some(&(line, _)) = map.iter().find(|&&(_, mapped)| mapped == addr) {
+                    frame["source"] = json!({ "path": path });
+                    frame["line"] = json!(line);
+                    frame["column"] = json!(1);
+                }
+            }
+            frame
+        }).collect();
+
+        json!({ "stackFrames": frames, "totalFrames": addrs.len() })
+    }
+
+    fn chip8(&self) -> Result<&Chip8, String> {
+        self.debugger.as_ref().map(Debugger::chip8).ok_or_else(|| "No program was launched".to_string())
+    }
+
+    fn with_debugger<F>(&mut self, run: F) -> Option<Result<StopReason, Chip8Error>>
+        where F: FnOnce(&mut Debugger) -> Result<StopReason, Chip8Error>
+    {
+        self.debugger.as_mut().map(run)
+    }
+
+    fn report(&mut self, reason: Option<Result<StopReason, Chip8Error>>) -> io::Result<()> {
+        match reason {
+            None => Ok(()),
+            Some(Ok(StopReason::Done)) => self.stopped("step", None),
+            Some(Ok(StopReason::Breakpoint(_))) => self.stopped("breakpoint", None),
+            Some(Ok(StopReason::Watchpoint { .. })) => self.stopped("data breakpoint", None),
+            Some(Ok(StopReason::WaitingForKey)) => self.stopped("pause", Some("Waiting for a key".to_string())),
+            Some(Ok(StopReason::CycleLimit)) => self.stopped("pause", Some("Cycle limit reached".to_string())),
+            Some(Ok(StopReason::Interrupted)) => self.stopped("pause", None),
+            Some(Ok(StopReason::Exited)) => {
+                self.event("exited", json!({ "exitCode": 0 }))?;
+                self.event("terminated", json!({}))
+            },
+            Some(Err(error)) => self.stopped("exception", Some(error.to_string())),
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+        }
+
+        self.event("stopped", body)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let content = message.to_string();
+
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", content.len(), content)?;
+        self.output.flush()
+    }
+
+    // Returns None once the editor closes the stream
+    fn read_message(&mut self) -> io::Result<Option<Value>> {
+        let mut length = None;
+
+        loop {
+            let mut header = String::new();
+            if self.input.read_line(&mut header)? == 0 {
+                return Ok(None);
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                if length.is_some() {
+                    break;
+                }
+                continue;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+
+        let mut content = vec![0; length.unwrap_or(0)];
+        self.input.read_exact(&mut content)?;
+
+        serde_json::from_slice(&content).map(Some).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+}
+
+fn variables(chip8: &Chip8) -> Value {
+    let mut variables: Vec<Value> = chip8.v().iter().enumerate()
+        .map(|(x, value)| variable(&format!("V{:X}", x), format!("0x{:02X}", value)))
+        .collect();
+
+    variables.push(variable("I", format!("0x{:04X}", chip8.i())));
+    variables.push(variable("PC", format!("0x{:04X}", chip8.pc())));
+    variables.push(variable("SP", chip8.sp().to_string()));
+    variables.push(variable("DT", chip8.delay_timer().to_string()));
+    variables.push(variable("ST", chip8.sound_timer.to_string()));
+
+    json!({ "variables": variables })
+}
+
+fn variable(name: &str, value: String) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Cursor;
+
+    // Runs a whole session and returns the messages the server sent
+    fn session(requests: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            let content = request.to_string();
+            write!(input, "Content-Length: {}\r\n\r\n{}", content.len(), content).unwrap();
+        }
+
+        let mut output = Vec::new();
+        DapServer::new(Cursor::new(input), &mut output).run().unwrap();
+
+        let mut messages = Vec::new();
+        let mut rest = &output[..];
+        while !rest.is_empty() {
+            let text = String::from_utf8_lossy(rest).into_owned();
+            let header_end = text.find("\r\n\r\n").unwrap();
+            let length: usize = text["Content-Length: ".len()..header_end].parse().unwrap();
+            let start = header_end + 4;
+            messages.push(serde_json::from_slice(&rest[start..(start + length)]).unwrap());
+            rest = &rest[(start + length)..];
+        }
+        messages
+    }
+
+    fn response<'a>(messages: &'a [Value], command: &str) -> &'a Value {
+        messages.iter().find(|message| message["command"] == command).unwrap()
+    }
+
+    fn stops(messages: &[Value]) -> Vec<&str> {
+        messages.iter()
+            .filter(|message| message["event"] == "stopped")
+            .map(|message| message["body"]["reason"].as_str().unwrap())
+            .collect()
+    }
+
+    fn write_program(name: &str, contents: &[u8]) -> String {
+        let path = env::temp_dir().join(format!("chip8-dap-{}-{}", std::process::id(), name));
+        fs::write(&path, contents).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_source_breakpoints() {
+        let path = write_program("source.asm", b"
+            LD V0, 7
+            CALL sub
+        end: JP end
+        sub:
+            ; comment
+            ADD V0, 1
+            RET
+        ");
+
+        let messages = session(&[
+            json!({ "command": "initialize", "arguments": { "adapterID": "chip8" } }),
+            json!({ "command": "launch", "arguments": { "program": path } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": path },
+                "breakpoints": [{ "line": 5 }, { "line": 100 }],
+            } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "stepIn", "arguments": { "threadId": 1 } }),
+            json!({ "command": "disconnect" }),
+        ]);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(messages[1]["event"], "initialized");
+        assert_eq!(response(&messages, "initialize")["body"]["supportsInstructionBreakpoints"], true);
+
+        let breakpoints = &response(&messages, "setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[0], json!({ "id": 1, "verified": true, "line": 7 }));
+        assert_eq!(breakpoints[1]["verified"], false);
+
+        assert_eq!(stops(&messages), vec!["breakpoint", "step"]);
+
+        let frames = &response(&messages, "stackTrace")["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "0x0206  ADD V0, 0x01");
+        assert_eq!(frames[0]["line"], 7);
+        assert_eq!(frames[0]["source"]["path"], path.as_str());
+        assert_eq!(frames[1]["name"], "0x0202  CALL 0x206");
+        assert_eq!(frames[1]["line"], 3);
+
+        let variables = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(variables[0], json!({ "name": "V0", "value": "0x07", "variablesReference": 0 }));
+        assert_eq!(variables[17]["value"], "0x0206");
+        assert_eq!(variables[18]["value"], "1");
+    }
+
+    #[test]
+    fn test_breakpoints_before_launch() {
+        let path = write_program("early.asm", b"
+            LD V0, 7
+        loop:
+            ADD V0, 1
+            JP loop
+        ");
+
+        // The order VS Code sends them in
+        let messages = session(&[
+            json!({ "command": "initialize" }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": path },
+                "breakpoints": [{ "line": 3 }, { "line": 100 }],
+            } }),
+            json!({ "command": "launch", "arguments": { "program": path } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "disconnect" }),
+        ]);
+        fs::remove_file(&path).unwrap();
+
+        let breakpoints = &response(&messages, "setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], false);
+        assert_eq!(breakpoints[1]["verified"], false);
+
+        let changed: Vec<&Value> = messages.iter()
+            .filter(|message| message["event"] == "breakpoint")
+            .map(|message| {
+                assert_eq!(message["body"]["reason"], "changed");
+                &message["body"]["breakpoint"]
+            })
+            .collect();
+        assert_eq!(changed.len(), 2);
+        assert_eq!(*changed[0], json!({ "id": breakpoints[0]["id"], "verified": true, "line": 4 }));
+        assert_eq!(changed[1]["id"], breakpoints[1]["id"]);
+        assert_eq!(changed[1]["verified"], false);
+
+        assert_eq!(stops(&messages), vec!["breakpoint"]);
+        assert_eq!(response(&messages, "variables")["body"]["variables"][0]["value"], "0x07");
+    }
+
+    #[test]
+    fn test_instruction_breakpoints() {
+        let path = write_program("rom.ch8", &[0x60, 0x01, 0x70, 0x01, 0x12, 0x02]);
+
+        let messages = session(&[
+            json!({ "command": "initialize" }),
+            json!({ "command": "launch", "arguments": { "program": path, "stopOnEntry": true } }),
+            json!({ "command": "setInstructionBreakpoints", "arguments": {
+                "breakpoints": [{ "instructionReference": "0x202" }],
+            } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "command": "continue", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "disconnect" }),
+        ]);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(stops(&messages), vec!["entry", "breakpoint", "breakpoint"]);
+        assert_eq!(response(&messages, "variables")["body"]["variables"][0]["value"], "0x02");
+    }
+
+    #[test]
+    fn test_errors() {
+        let messages = session(&[
+            json!({ "command": "launch", "arguments": { "program": "/nonexistent/rom.ch8" } }),
+            json!({ "command": "stackTrace" }),
+            json!({ "command": "evaluate" }),
+        ]);
+
+        assert_eq!(response(&messages, "launch")["success"], false);
+        assert_eq!(response(&messages, "stackTrace")["message"], "No program was launched");
+        assert_eq!(response(&messages, "evaluate")["message"], "evaluate isn't supported");
+    }
+}
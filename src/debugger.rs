@@ -0,0 +1,544 @@
+// A debugger wrapped around the VM: breakpoints on the program counter,
+// watchpoints on memory and registers, and the usual ways of stepping.
+//
+// Watchpoints are checked by working out, before an instruction runs, which
+// registers and bytes of RAM it is going to read and write. The debugger stops
+// once the instruction has run, like a hardware watchpoint would.
+
+use alloc::collections::{BTreeMap, BTreeSet};
+
+use crate::{Chip8, Chip8Error, Instruction, StepOutcome, OPCODE_SIZE};
+
+// Running stops after this many instructions, so an endless loop can't hang
+// the debugger
+const DEFAULT_CYCLE_LIMIT: usize = 1_000_000;
+// How many instructions the debugger runs between two ticks of the timers,
+// the same rate the emulator runs them at
+const DEFAULT_INSTRUCTIONS_PER_FRAME: usize = 10;
+// How often `resume_interruptible` asks whether to stop
+const INTERRUPT_INTERVAL: usize = 1000;
+
+// Something a watchpoint can be set on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Watch {
+    Memory(usize),
+    // V0 to VF
+    Register(u8),
+}
+
+// The kind of access a watchpoint stops on, or the one that made it stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn reads(self) -> bool {
+        self != Access::Write
+    }
+
+    fn writes(self) -> bool {
+        self != Access::Read
+    }
+}
+
+// Why the debugger handed control back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // The step, step over, step out or run until completed
+    Done,
+    // The program counter reached a breakpoint, which hasn't run yet
+    Breakpoint(usize),
+    // The last instruction accessed a watched register or address
+    Watchpoint { watch: Watch, access: Access },
+    WaitingForKey,
+    Exited,
+    CycleLimit,
+    // The client asked for the program to stop
+    Interrupted,
+}
+
+pub struct Debugger {
+    chip8: Chip8,
+    breakpoints: BTreeSet<usize>,
+    watchpoints: BTreeMap<Watch, Access>,
+    cycle_limit: usize,
+    instructions_per_frame: usize,
+    cycles: u64,
+}
+
+impl Debugger {
+    pub fn new(chip8: Chip8) -> Debugger {
+        Debugger {
+            chip8,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            cycle_limit: DEFAULT_CYCLE_LIMIT,
+            instructions_per_frame: DEFAULT_INSTRUCTIONS_PER_FRAME,
+            cycles: 0,
+        }
+    }
+
+    pub fn chip8(&self) -> &Chip8 {
+        &self.chip8
+    }
+
+    pub fn chip8_mut(&mut self) -> &mut Chip8 {
+        &mut self.chip8
+    }
+
+    pub fn into_inner(self) -> Chip8 {
+        self.chip8
+    }
+
+    // Instructions run since the debugger was created
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn set_cycle_limit(&mut self, limit: usize) {
+        self.cycle_limit = limit;
+    }
+
+    pub fn set_instructions_per_frame(&mut self, instructions: usize) {
+        self.instructions_per_frame = instructions.max(1);
+    }
+
+    // Returns false if there already was a breakpoint at `addr`
+    pub fn add_breakpoint(&mut self, addr: usize) -> bool {
+        self.breakpoints.insert(addr)
+    }
+
+    // Returns false if there was no breakpoint at `addr`
+    pub fn remove_breakpoint(&mut self, addr: usize) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().cloned()
+    }
+
+    pub fn add_watchpoint(&mut self, watch: Watch, access: Access) {
+        self.watchpoints.insert(watch, access);
+    }
+
+    // Returns false if nothing was watched there
+    pub fn remove_watchpoint(&mut self, watch: Watch) -> bool {
+        self.watchpoints.remove(&watch).is_some()
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (Watch, Access)> + '_ {
+        self.watchpoints.iter().map(|(&watch, &access)| (watch, access))
+    }
+
+    // Runs a single instruction, ignoring breakpoints
+    pub fn step(&mut self) -> Result<StopReason, Chip8Error> {
+        let waiting = self.chip8.keypad_waiting;
+        let mut accesses = if waiting { Accesses::default() } else { Accesses::of(&self.chip8)? };
+
+        let outcome = self.chip8.cycle()?;
+        // FX0A writes its register on the cycle that ends the wait, not the
+        // one that starts it
+        if waiting && !self.chip8.keypad_waiting {
+            accesses.registers_written = 1 << self.chip8.keypad_register;
+        }
+
+        self.cycles += 1;
+        if self.cycles.is_multiple_of(self.instructions_per_frame as u64) {
+            self.chip8.tick_timers();
+        }
+
+        if let Some((watch, access)) = self.triggered(&accesses) {
+            return Ok(StopReason::Watchpoint { watch, access });
+        }
+
+        Ok(match outcome {
+            StepOutcome::Running => StopReason::Done,
+            StepOutcome::WaitingForKey => StopReason::WaitingForKey,
+            StepOutcome::Exited => StopReason::Exited,
+        })
+    }
+
+    // Runs a whole subroutine if the next instruction calls one, and is a
+    // plain step otherwise
+    pub fn step_over(&mut self) -> Result<StopReason, Chip8Error> {
+        let opcode = self.chip8.read_opcode()?;
+
+        match Instruction::decode(opcode) {
+            Some(Instruction::Call(_)) => {
+                let addr = self.chip8.pc + OPCODE_SIZE;
+                let depth = self.chip8.sp;
+                self.run(|chip8| chip8.pc == addr && chip8.sp == depth)
+            },
+            _ => self.step(),
+        }
+    }
+
+    // Runs until the current subroutine returns. Outside of a subroutine it
+    // runs until something else stops it.
+    pub fn step_out(&mut self) -> Result<StopReason, Chip8Error> {
+        let depth = self.chip8.sp;
+
+        self.run(|chip8| chip8.sp < depth)
+    }
+
+    // Runs until the program counter reaches `addr`
+    pub fn run_until(&mut self, addr: usize) -> Result<StopReason, Chip8Error> {
+        self.run(|chip8| chip8.pc == addr)
+    }
+
+    // Runs until a breakpoint, a watchpoint or the cycle limit
+    pub fn resume(&mut self) -> Result<StopReason, Chip8Error> {
+        self.run(|_| false)
+    }
+
+    // Like `resume`, but also stops once `interrupted` says so. It's only
+    // asked every so many instructions, so it can afford to do I/O.
+    pub fn resume_interruptible<F: FnMut() -> bool>(&mut self, mut interrupted: F) -> Result<StopReason, Chip8Error> {
+        let mut cycles = 0;
+        let reason = self.run(|_| {
+            cycles += 1;
+            cycles % INTERRUPT_INTERVAL == 0 && interrupted()
+        })?;
+
+        Ok(match reason {
+            StopReason::Done => StopReason::Interrupted,
+            reason => reason,
+        })
+    }
+
+    fn run<F: FnMut(&Chip8) -> bool>(&mut self, mut done: F) -> Result<StopReason, Chip8Error> {
+        for cycle in 0..self.cycle_limit {
+            // Carrying on from a breakpoint doesn't stop on it straight away
+            if cycle > 0 && self.breakpoints.contains(&self.chip8.pc) {
+                return Ok(StopReason::Breakpoint(self.chip8.pc));
+            }
+
+            match self.step()? {
+                StopReason::Done => {},
+                reason => return Ok(reason),
+            }
+
+            if done(&self.chip8) {
+                return Ok(StopReason::Done);
+            }
+        }
+
+        Ok(StopReason::CycleLimit)
+    }
+
+    fn triggered(&self, accesses: &Accesses) -> Option<(Watch, Access)> {
+        self.watchpoints.iter().find_map(|(&watch, &access)| {
+            let (read, written) = match watch {
+                Watch::Memory(addr) => (
+                    accesses.memory_read.contains(&addr),
+                    accesses.memory_written.contains(&addr),
+                ),
+                Watch::Register(x) if x < 16 => (
+                    accesses.registers_read & (1 << x) != 0,
+                    accesses.registers_written & (1 << x) != 0,
+                ),
+                Watch::Register(_) => (false, false),
+            };
+
+            if access.writes() && written {
+                Some((watch, Access::Write))
+            } else if access.reads() && read {
+                Some((watch, Access::Read))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+// The registers and RAM the next instruction is going to touch. Registers are
+// bit masks, V0 being the lowest bit.
+#[derive(Default)]
+struct Accesses {
+    registers_read: u16,
+    registers_written: u16,
+    memory_read: core::ops::Range<usize>,
+    memory_written: core::ops::Range<usize>,
+}
+
+impl Accesses {
+    fn of(chip8: &Chip8) -> Result<Accesses, Chip8Error> {
+        let opcode = chip8.read_opcode()?;
+        let mut accesses = Accesses::default();
+        let instruction = match Instruction::decode(opcode) {
+            Some(instruction) => instruction,
+            None => return Ok(accesses),
+        };
+
+        let quirks = chip8.quirks;
+        let i = chip8.i as usize;
+        // Registers x to y, in either order
+        let range = |x: u8, y: u8| -> u16 {
+            let (low, high) = if x <= y { (x, y) } else { (y, x) };
+            (low..=high).fold(0, |mask, r| mask | 1 << r)
+        };
+        let reg = |x: u8| 1u16 << x;
+        let flag = reg(0xF);
+
+        match instruction {
+            Instruction::SkipEqByte(x, _)
+            | Instruction::SkipNeByte(x, _)
+            | Instruction::SkipKey(x)
+            | Instruction::SkipNotKey(x)
+            | Instruction::SetDelay(x)
+            | Instruction::SetSound(x)
+            | Instruction::LoadFont(x)
+            | Instruction::LoadBigFont(x)
+            | Instruction::Pitch(x) => accesses.registers_read = reg(x),
+            Instruction::SkipEqReg(x, y) | Instruction::SkipNeReg(x, y) =>
+                accesses.registers_read = reg(x) | reg(y),
+            Instruction::SaveRange(x, y) => {
+                accesses.registers_read = range(x, y);
+                accesses.memory_written = i..(i + range(x, y).count_ones() as usize);
+            },
+            Instruction::LoadRange(x, y) => {
+                accesses.registers_written = range(x, y);
+                accesses.memory_read = i..(i + range(x, y).count_ones() as usize);
+            },
+            Instruction::LoadByte(x, _)
+            | Instruction::Random(x, _)
+            | Instruction::LoadDelay(x) => accesses.registers_written = reg(x),
+            Instruction::AddByte(x, _) => {
+                accesses.registers_read = reg(x);
+                accesses.registers_written = reg(x);
+            },
+            Instruction::LoadReg(x, y) => {
+                accesses.registers_read = reg(y);
+                accesses.registers_written = reg(x);
+            },
+            Instruction::Or(x, y) | Instruction::And(x, y) | Instruction::Xor(x, y) => {
+                accesses.registers_read = reg(x) | reg(y);
+                accesses.registers_written = reg(x);
+                if quirks.logic_resets_vf {
+                    accesses.registers_written |= flag;
+                }
+            },
+            Instruction::AddReg(x, y) | Instruction::Sub(x, y) | Instruction::Subn(x, y) => {
+                accesses.registers_read = reg(x) | reg(y);
+                accesses.registers_written = reg(x) | flag;
+            },
+            Instruction::Shr(x, y) | Instruction::Shl(x, y) => {
+                accesses.registers_read = if quirks.shift_uses_vy { reg(y) } else { reg(x) };
+                accesses.registers_written = reg(x) | flag;
+            },
+            Instruction::JumpV0(nnn) => {
+                let x = if quirks.jump_uses_vx { (nnn >> 8) as u8 } else { 0 };
+                accesses.registers_read = reg(x);
+            },
+            Instruction::Draw(x, y, n) => {
+                let sprite_size = if n == 0 { 32 } else { n as usize };
+                accesses.registers_read = reg(x) | reg(y);
+                accesses.registers_written = flag;
+                accesses.memory_read = i..(i + sprite_size * chip8.plane.count_ones() as usize);
+            },
+            Instruction::Audio => accesses.memory_read = i..(i + 16),
+            Instruction::AddI(x) => {
+                accesses.registers_read = reg(x);
+                if i + chip8.v[x as usize] as usize > 0xFFFF {
+                    accesses.registers_written = flag;
+                }
+            },
+            Instruction::Bcd(x) => {
+                accesses.registers_read = reg(x);
+                accesses.memory_written = i..(i + 3);
+            },
+            Instruction::Store(x) => {
+                accesses.registers_read = range(0, x);
+                accesses.memory_written = i..(i + x as usize + 1);
+            },
+            Instruction::Load(x) => {
+                accesses.registers_written = range(0, x);
+                accesses.memory_read = i..(i + x as usize + 1);
+            },
+            Instruction::StoreFlags(x) => accesses.registers_read = range(0, x),
+            Instruction::LoadFlags(x) => accesses.registers_written = range(0, x),
+            _ => {},
+        }
+
+        Ok(accesses)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, START_PC};
+
+    fn debugger(source: &str) -> Debugger {
+        let mut chip8 = Chip8::new();
+        chip8.load(&assemble(source).unwrap().rom);
+
+        Debugger::new(chip8)
+    }
+
+    const PROGRAM: &str = "
+                LD V0, 1        ; 0x200
+                CALL double     ; 0x202
+                CALL double     ; 0x204
+        end:    JP end          ; 0x206
+        double: ADD V0, V0      ; 0x208
+                CALL nothing    ; 0x20A
+                RET             ; 0x20C
+        nothing: RET            ; 0x20E
+    ";
+
+    #[test]
+    fn test_breakpoints() {
+        let mut debugger = debugger(PROGRAM);
+
+        assert!(debugger.add_breakpoint(0x208));
+        assert!(!debugger.add_breakpoint(0x208));
+        assert_eq!(debugger.resume().unwrap(), StopReason::Breakpoint(0x208));
+        assert_eq!(debugger.chip8().stack(), &[0x204]);
+
+        // Carrying on runs past the breakpoint until it's hit again
+        assert_eq!(debugger.resume().unwrap(), StopReason::Breakpoint(0x208));
+        assert_eq!(debugger.chip8().stack(), &[0x206]);
+        assert_eq!(debugger.chip8().v()[0], 2);
+
+        assert!(debugger.remove_breakpoint(0x208));
+        assert!(!debugger.remove_breakpoint(0x208));
+        debugger.set_cycle_limit(100);
+        assert_eq!(debugger.resume().unwrap(), StopReason::CycleLimit);
+        assert_eq!(debugger.chip8().pc(), 0x206);
+    }
+
+    #[test]
+    fn test_step() {
+        let mut debugger = debugger(PROGRAM);
+
+        assert_eq!(debugger.step().unwrap(), StopReason::Done);
+        assert_eq!(debugger.step().unwrap(), StopReason::Done);
+        assert_eq!(debugger.chip8().pc(), 0x208);
+        assert_eq!(debugger.cycles(), 2);
+    }
+
+    #[test]
+    fn test_step_over() {
+        let mut debugger = debugger(PROGRAM);
+
+        debugger.step().unwrap();
+        assert_eq!(debugger.step_over().unwrap(), StopReason::Done);
+        assert_eq!(debugger.chip8().pc(), 0x204);
+        assert_eq!(debugger.chip8().v()[0], 2);
+
+        // Breakpoints inside the subroutine still stop it
+        debugger.add_breakpoint(0x20E);
+        assert_eq!(debugger.step_over().unwrap(), StopReason::Breakpoint(0x20E));
+    }
+
+    #[test]
+    fn test_step_out() {
+        let mut debugger = debugger(PROGRAM);
+
+        debugger.run_until(0x20E).unwrap();
+        assert_eq!(debugger.chip8().stack(), &[0x204, 0x20C]);
+
+        assert_eq!(debugger.step_out().unwrap(), StopReason::Done);
+        assert_eq!(debugger.chip8().pc(), 0x20C);
+        assert_eq!(debugger.step_out().unwrap(), StopReason::Done);
+        assert_eq!(debugger.chip8().pc(), 0x204);
+        assert_eq!(debugger.chip8().sp(), 0);
+    }
+
+    #[test]
+    fn test_run_until() {
+        let mut debugger = debugger(PROGRAM);
+
+        assert_eq!(debugger.run_until(0x206).unwrap(), StopReason::Done);
+        assert_eq!(debugger.chip8().v()[0], 4);
+        assert_eq!(debugger.chip8().pc(), 0x206);
+    }
+
+    #[test]
+    fn test_memory_watchpoints() {
+        let mut debugger = debugger("
+            LD I, 0x300
+            LD V0, 1
+            LD V1, 2
+            LD [I], V1      ; writes 0x300 and 0x301
+            LD V1, [I]      ; reads 0x300 and 0x301
+            JP 0x20A
+        ");
+
+        debugger.add_watchpoint(Watch::Memory(0x301), Access::Write);
+        assert_eq!(debugger.resume().unwrap(),
+                   StopReason::Watchpoint { watch: Watch::Memory(0x301), access: Access::Write });
+        assert_eq!(debugger.chip8().pc(), 0x208);
+        assert_eq!(debugger.chip8().memory()[0x301], 2);
+
+        debugger.set_cycle_limit(10);
+        assert_eq!(debugger.resume().unwrap(), StopReason::CycleLimit);
+
+        let mut debugger = debugger_at_start(debugger);
+        debugger.add_watchpoint(Watch::Memory(0x301), Access::Read);
+        assert_eq!(debugger.resume().unwrap(),
+                   StopReason::Watchpoint { watch: Watch::Memory(0x301), access: Access::Read });
+        assert_eq!(debugger.chip8().pc(), 0x20A);
+    }
+
+    fn debugger_at_start(debugger: Debugger) -> Debugger {
+        let mut chip8 = debugger.into_inner();
+        chip8.pc = START_PC;
+
+        Debugger::new(chip8)
+    }
+
+    #[test]
+    fn test_register_watchpoints() {
+        let mut debugger = debugger("
+            LD V1, 5
+            LD V2, V1
+            ADD V3, V2      ; reads V3, writes V3 and VF
+            JP 0x204
+        ");
+
+        debugger.add_watchpoint(Watch::Register(1), Access::Read);
+        debugger.add_watchpoint(Watch::Register(0xF), Access::ReadWrite);
+        assert_eq!(debugger.resume().unwrap(),
+                   StopReason::Watchpoint { watch: Watch::Register(1), access: Access::Read });
+        assert_eq!(debugger.chip8().v()[2], 5);
+
+        assert_eq!(debugger.resume().unwrap(),
+                   StopReason::Watchpoint { watch: Watch::Register(0xF), access: Access::Write });
+        assert_eq!(debugger.chip8().pc(), 0x206);
+
+        assert!(debugger.remove_watchpoint(Watch::Register(0xF)));
+        assert_eq!(debugger.watchpoints().collect::<Vec<_>>(), vec![(Watch::Register(1), Access::Read)]);
+    }
+
+    #[test]
+    fn test_register_watchpoint_across_wait_key() {
+        let mut debugger = debugger("
+            LD V3, K
+            JP 0x202
+        ");
+        debugger.add_watchpoint(Watch::Register(3), Access::Write);
+
+        // Starting the wait doesn't write V3 yet
+        assert_eq!(debugger.step().unwrap(), StopReason::WaitingForKey);
+        assert_eq!(debugger.step().unwrap(), StopReason::WaitingForKey);
+
+        debugger.chip8_mut().keypad[7] = true;
+        assert_eq!(debugger.step().unwrap(), StopReason::WaitingForKey);
+        debugger.chip8_mut().keypad[7] = false;
+        assert_eq!(debugger.step().unwrap(),
+                   StopReason::Watchpoint { watch: Watch::Register(3), access: Access::Write });
+        assert_eq!(debugger.chip8().v()[3], 7);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut debugger = debugger("RET");
+
+        assert_eq!(debugger.step(), Err(Chip8Error::StackUnderflow));
+    }
+}
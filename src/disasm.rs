@@ -0,0 +1,113 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::Instruction;
+
+// One line of a ROM listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmLine {
+    pub addr: usize,
+    pub bytes: Vec<u8>,
+    // None when the bytes aren't an instruction, they are listed as data then
+    pub instruction: Option<Instruction>,
+}
+
+impl fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex: Vec<String> = self.bytes
+            .chunks(2)
+            .map(|word| word.iter().map(|byte| format!("{:02X}", byte)).collect())
+            .collect();
+
+        write!(f, "0x{:04X}  {:<9}  ", self.addr, hex.join(" "))?;
+
+        match self.instruction {
+            Some(Instruction::LoadLongI) if self.bytes.len() == 4 =>
+                write!(f, "LD I, long 0x{:02X}{:02X}", self.bytes[2], self.bytes[3]),
+            Some(instruction) => write!(f, "{}", instruction),
+            None => {
+                let data: Vec<String> = self.bytes
+                    .iter()
+                    .map(|byte| format!("0x{:02X}", byte))
+                    .collect();
+                write!(f, "db {}", data.join(", "))
+            },
+        }
+    }
+}
+
+// Lists a whole ROM loaded at `origin`, two bytes at a time. Sprites and other
+// data that happen to look like instructions are listed as instructions, the
+// rest is marked as data.
+pub fn disassemble(rom: &[u8], origin: usize) -> Vec<DisasmLine> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+
+    while offset < rom.len() {
+        let decoded = if offset + 1 < rom.len() {
+            Instruction::decode((rom[offset] as u16) << 8 | rom[offset + 1] as u16)
+        } else {
+            None
+        };
+
+        let (instruction, size) = match decoded {
+            // F000 is followed by a 16 bit address
+            Some(Instruction::LoadLongI) if offset + 3 >= rom.len() => (None, 2),
+            Some(Instruction::LoadLongI) => (decoded, 4),
+            _ => (decoded, 2),
+        };
+
+        let end = (offset + size).min(rom.len());
+
+        lines.push(DisasmLine {
+            addr: origin + offset,
+            bytes: rom[offset..end].to_vec(),
+            instruction,
+        });
+        offset = end;
+    }
+
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let rom = [
+            0x00, 0xE0,
+            0x65, 0x1F,
+            0xFF, 0xFF,
+            0xF0, 0x00, 0xBE, 0xEF,
+            0xD0, 0x13,
+            0x42,
+        ];
+
+        let listing: Vec<String> = disassemble(&rom, 0x200)
+            .iter()
+            .map(|line| line.to_string())
+            .collect();
+
+        assert_eq!(listing, vec![
+            "0x0200  00E0       CLS",
+            "0x0202  651F       LD V5, 0x1F",
+            "0x0204  FFFF       db 0xFF, 0xFF",
+            "0x0206  F000 BEEF  LD I, long 0xBEEF",
+            "0x020A  D013       DRW V0, V1, 3",
+            "0x020C  42         db 0x42",
+        ]);
+    }
+
+    #[test]
+    fn test_truncated_long_load() {
+        let lines = disassemble(&[0xF0, 0x00, 0x12], 0x200);
+
+        assert_eq!(lines[0].instruction, None);
+        assert_eq!(lines[0].bytes, vec![0xF0, 0x00]);
+        assert_eq!(lines[1].bytes, vec![0x12]);
+    }
+}
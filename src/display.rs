@@ -1,62 +1,97 @@
-use sdl2;
 use sdl2::Sdl;
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
 
-use chip_8::CHIP8_WIDTH;
-use chip_8::CHIP8_HEIGHT;
+use chip_8::HIRES_WIDTH;
+use chip_8::HIRES_HEIGHT;
+use chip_8::{FrontendError, Screen, VideoSink};
 
-const SCALE: u32 = 20;
-const DISPLAY_WIDTH: u32 = (CHIP8_WIDTH as u32) * SCALE;
-const DISPLAY_HEIGHT: u32 = (CHIP8_HEIGHT as u32) * SCALE;
+use crate::cli::Rgb;
 
 pub struct Display {
     canvas: Canvas<Window>,
+    // The size of a high resolution pixel in the window, low resolution ones
+    // are twice as big
+    hires_scale: u32,
+    // Indexed by the bitplanes a pixel is lit on
+    palette: [Color; 4],
+    vsync: bool,
 }
 
 impl Display {
-    pub fn new(sdl_context: &Sdl) -> Display {
-        let video_subsystem = sdl_context.video().unwrap();
+    // `scale` is the size of a low resolution pixel in the window, rounded up
+    // to an even number so high resolution pixels fill whole window pixels
+    // too. With `vsync` presenting waits for the vertical blank of the display.
+    pub fn new(sdl_context: &Sdl, scale: u32, palette: [Rgb; 4], vsync: bool) -> Result<Display, String> {
+        let video_subsystem = sdl_context.video()?;
 
-        let window = video_subsystem.window("Chip-8", DISPLAY_WIDTH, DISPLAY_HEIGHT)
+        let hires_scale = scale.div_ceil(2).max(1);
+        let width = (HIRES_WIDTH as u32) * hires_scale;
+        let height = (HIRES_HEIGHT as u32) * hires_scale;
+        let window = video_subsystem.window("Chip-8", width, height)
             .position_centered()
             .opengl()
             .build()
-            .unwrap();
+            .map_err(|error| error.to_string())?;
 
-        let mut canvas = window.into_canvas().build().unwrap();
+        let mut builder = window.into_canvas();
+        if vsync {
+            builder = builder.present_vsync();
+        }
+        let mut canvas = builder.build().map_err(|error| error.to_string())?;
+
+        let mut colors = [Color::RGB(0, 0, 0); 4];
+        for (color, &(r, g, b)) in colors.iter_mut().zip(palette.iter()) {
+            *color = Color::RGB(r, g, b);
+        }
 
-        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.set_draw_color(colors[0]);
         canvas.clear();
         canvas.present();
 
-        Display {
+        Ok(Display {
             canvas,
-        }
+            hires_scale,
+            palette: colors,
+            vsync,
+        })
     }
+}
+
+impl VideoSink for Display {
+    fn present(&mut self, screen: &Screen) -> Result<(), FrontendError> {
+        // The window keeps its size when the resolution changes
+        let scale = if screen.is_hires() { self.hires_scale } else { self.hires_scale * 2 };
 
-    pub fn draw(&mut self, screen: &[[u8; CHIP8_WIDTH]; CHIP8_HEIGHT]) {
-        for (y, row) in screen.iter().enumerate() {
-            for (x, &col) in row.iter().enumerate() {
-                let x = (x as u32) * SCALE;
-                let y = (y as u32) * SCALE;
+        // The background in one go, and then the lit pixels of each colour in
+        // one call each
+        let mut lit: [Vec<Rect>; 4] = Default::default();
+        for (y, row) in screen.rows().enumerate() {
+            for (x, &col) in row.iter().enumerate().filter(|&(_, &col)| col & 0b11 != 0) {
+                let x = (x as u32) * scale;
+                let y = (y as u32) * scale;
 
-                self.canvas.set_draw_color(color(col));
-                let _ = self.canvas
-                    .fill_rect(Rect::new(x as i32, y as i32, SCALE, SCALE));
+                lit[(col & 0b11) as usize].push(Rect::new(x as i32, y as i32, scale, scale));
+            }
+        }
+
+        self.canvas.set_draw_color(self.palette[0]);
+        self.canvas.clear();
+        for (&color, rects) in self.palette.iter().zip(lit.iter()) {
+            if !rects.is_empty() {
+                self.canvas.set_draw_color(color);
+                self.canvas.fill_rects(rects).map_err(FrontendError::Backend)?;
             }
         }
 
         self.canvas.present();
+
+        Ok(())
     }
-}
 
-fn color(value: u8) -> Color {
-    if value == 0 {
-        Color::RGB(0, 0, 0)
-    } else {
-        Color::RGB(0, 250, 0)
+    fn vsync(&self) -> bool {
+        self.vsync
     }
 }
@@ -0,0 +1,144 @@
+use alloc::string::String;
+#[cfg(feature = "std")]
+use alloc::string::ToString;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+#[cfg(feature = "std")]
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Chip8Error {
+    // A 2NNN call with every level of the stack already in use
+    StackOverflow,
+    // A 00EE return with nothing on the stack
+    StackUnderflow,
+    // An instruction tried to touch memory past the end of the RAM
+    MemoryOutOfBounds { addr: usize },
+    // The opcode doesn't match any instruction the VM knows about
+    UnknownOpcode { opcode: u16, pc: usize },
+    // The program counter no longer points to a full opcode in RAM
+    PcOutOfRange,
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Chip8Error::StackOverflow => write!(f, "Stack overflow"),
+            Chip8Error::StackUnderflow => write!(f, "Stack underflow"),
+            Chip8Error::MemoryOutOfBounds { addr } =>
+                write!(f, "Memory access out of bounds at address {:x}", addr),
+            Chip8Error::UnknownOpcode { opcode, pc } =>
+                write!(f, "Unknown opcode {:x} in memory address {:x}", opcode, pc),
+            Chip8Error::PcOutOfRange => write!(f, "Program counter out of range"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for Chip8Error {}
+
+// Why `Chip8::load_state` rejected a snapshot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StateError {
+    // The data doesn't start with the save state magic bytes
+    BadMagic,
+    // The snapshot was written by a newer, unknown version of the format
+    UnsupportedVersion(u16),
+    // The data ends before the snapshot does
+    Truncated,
+    // There is more data after the end of the snapshot
+    TrailingData,
+    // The snapshot was taken with a different amount of RAM
+    MemorySizeMismatch { expected: usize, found: usize },
+    // A field holds a value the VM could never be in
+    Invalid,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) =>
+                write!(f, "Unsupported save state version {}", version),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::TrailingData => write!(f, "Save state has data past its end"),
+            StateError::MemorySizeMismatch { expected, found } =>
+                write!(f, "Save state has {} bytes of RAM, expected {}", found, expected),
+            StateError::Invalid => write!(f, "Save state is corrupted"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for StateError {}
+
+// A problem in assembler source, with the line it was found on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    // Counted from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for AsmError {}
+
+// A problem in a key script, with the line it was found on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptError {
+    // Counted from 1
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for ScriptError {}
+
+// Why `RunLoop::run` stopped early
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrontendError {
+    Chip8(Chip8Error),
+    // A backend failed, with its own description of the problem
+    Backend(String),
+}
+
+#[cfg(feature = "std")]
+impl fmt::Display for FrontendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrontendError::Chip8(error) => error.fmt(f),
+            FrontendError::Backend(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for FrontendError {}
+
+#[cfg(feature = "std")]
+impl From<Chip8Error> for FrontendError {
+    fn from(error: Chip8Error) -> FrontendError {
+        FrontendError::Chip8(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for FrontendError {
+    fn from(error: io::Error) -> FrontendError {
+        FrontendError::Backend(error.to_string())
+    }
+}
@@ -0,0 +1,473 @@
+// What a frontend plugs into the VM: somewhere to show the screen, something
+// to make the sound and something to read the keypad from. `RunLoop` drives
+// the VM one 60 Hz frame at a time over any three of them, so SDL, the
+// terminal and the headless runner are just different backends.
+
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::{Chip8, FrontendError, Rewind, Screen, StepOutcome, Tracer};
+
+// After a stall, this many frames are run back to back to catch up, and any
+// more are dropped
+const MAX_CATCH_UP: u32 = 5;
+
+pub trait VideoSink {
+    // Called at most once per frame, and only when the screen changed since
+    // the last time
+    fn present(&mut self, screen: &Screen) -> Result<(), FrontendError>;
+
+    // Called instead of `present` on frames where the screen didn't change,
+    // for sinks that have to redraw for reasons of their own
+    fn present_unchanged(&mut self, _screen: &Screen) -> Result<(), FrontendError> {
+        Ok(())
+    }
+
+    // Whether `present` waits for the vertical blank of the display, in which
+    // case the loop doesn't sleep between frames itself
+    fn vsync(&self) -> bool {
+        false
+    }
+}
+
+pub trait AudioSink {
+    // Called once per frame. The sound plays while `sound_timer` is above 0,
+    // as the XO-CHIP `pattern` at `pitch` if there is one, or as a buzzer.
+    fn play(&mut self, sound_timer: u8, pattern: Option<[u8; 16]>, pitch: u8) -> Result<(), FrontendError>;
+}
+
+// What the player wants the loop to do next
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Play,
+    // Step back one frame instead of running one
+    Rewind,
+    // Stop running the program, or carry on if it's stopped
+    Pause,
+    Quit,
+}
+
+pub trait InputSource {
+    // Reads the pending input, it's called once at the start of every frame
+    fn poll(&mut self) -> Result<Control, FrontendError>;
+
+    // The keys held down, as of the last poll
+    fn keypad(&self) -> [bool; 16];
+}
+
+pub struct RunLoop {
+    pub instructions_per_frame: usize,
+    // How long a frame lasts, nothing means as fast as possible
+    pub frame: Option<Duration>,
+    // Stop after this many instructions
+    pub cycle_limit: Option<u64>,
+    // Remembers the frames that are played, so that they can be rewound
+    pub rewind: Option<Rewind>,
+    pub tracer: Option<Tracer<Box<dyn Write>>>,
+    // The screen is still presented, but no instructions run
+    pub paused: bool,
+    cycles: u64,
+}
+
+impl RunLoop {
+    pub fn new(instructions_per_frame: usize) -> RunLoop {
+        RunLoop {
+            instructions_per_frame,
+            frame: None,
+            cycle_limit: None,
+            rewind: None,
+            tracer: None,
+            paused: false,
+            cycles: 0,
+        }
+    }
+
+    // Instructions run so far
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // Plays until the input quits, the program exits or the cycle limit is
+    // reached
+    pub fn run<V, A, I>(&mut self, chip8: &mut Chip8, video: &mut V, audio: &mut A, input: &mut I)
+        -> Result<(), FrontendError>
+        where V: VideoSink, A: AudioSink, I: InputSource
+    {
+        let mut clock = self.frame.map(Clock::new);
+        // The screen generation that was presented last
+        let mut presented = None;
+
+        loop {
+            let frames = match clock.as_mut() {
+                Some(clock) => clock.due(Instant::now()),
+                None => 1,
+            };
+            // Inputs latch a tap until the next poll, so polling waits until
+            // there's a frame to play it in
+            if let Some(clock) = clock.as_ref().filter(|_| frames == 0) {
+                clock.sleep();
+                continue;
+            }
+            let control = input.poll()?;
+
+            let mut outcome = StepOutcome::Running;
+            match control {
+                Control::Quit => return Ok(()),
+                Control::Pause => self.paused = !self.paused,
+                Control::Rewind => for _ in 0..frames {
+                    if let Some(rewind) = self.rewind.as_mut() {
+                        rewind.rewind(chip8);
+                    }
+                },
+                Control::Play if self.paused => {},
+                Control::Play => {
+                    chip8.set_keypad(input.keypad());
+                    for _ in 0..frames {
+                        outcome = self.run_frame(chip8)?;
+                        if let Some(rewind) = self.rewind.as_mut() {
+                            rewind.push(chip8);
+                        }
+                        if outcome == StepOutcome::Exited || self.limited() {
+                            break;
+                        }
+                    }
+                },
+            }
+
+            let changed = presented != Some(chip8.screen_generation());
+            if changed {
+                video.present(&chip8.screen)?;
+                presented = Some(chip8.screen_generation());
+            } else {
+                video.present_unchanged(&chip8.screen)?;
+            }
+            audio.play(chip8.sound_timer, chip8.audio_pattern, chip8.pitch)?;
+
+            if outcome == StepOutcome::Exited || self.limited() {
+                return Ok(());
+            }
+
+            // Presenting already waited for the display otherwise
+            if let Some(clock) = clock.as_ref().filter(|_| !changed || !video.vsync()) {
+                clock.sleep();
+            }
+        }
+    }
+
+    fn limited(&self) -> bool {
+        self.cycle_limit.is_some_and(|limit| self.cycles >= limit)
+    }
+
+    // `Chip8::run_frame`, counting the instructions and tracing them
+    fn run_frame(&mut self, chip8: &mut Chip8) -> Result<StepOutcome, FrontendError> {
+        let mut instructions = self.instructions_per_frame as u64;
+        if let Some(limit) = self.cycle_limit {
+            instructions = instructions.min(limit.saturating_sub(self.cycles));
+        }
+
+        let mut outcome = StepOutcome::Running;
+        for _ in 0..instructions {
+            outcome = match self.tracer.as_mut() {
+                Some(tracer) => tracer.cycle(chip8)?,
+                None => chip8.cycle()?,
+            };
+            self.cycles += 1;
+            if outcome != StepOutcome::Running {
+                break;
+            }
+        }
+
+        chip8.tick_timers();
+
+        Ok(outcome)
+    }
+}
+
+// Frame deadlines on a monotonic clock, so the speed doesn't depend on how
+// long the frames take to run and present or on how long sleeps overshoot
+struct Clock {
+    frame: Duration,
+    // When the next frame is due
+    next: Instant,
+}
+
+impl Clock {
+    fn new(frame: Duration) -> Clock {
+        Clock { frame, next: Instant::now() }
+    }
+
+    // How many frames are due at `now`, and moves on to the next deadline
+    fn due(&mut self, now: Instant) -> u32 {
+        if now < self.next {
+            return 0;
+        }
+
+        let behind = (now - self.next).as_nanos() / self.frame.as_nanos().max(1);
+        if behind >= MAX_CATCH_UP as u128 {
+            self.next = now + self.frame;
+            return MAX_CATCH_UP;
+        }
+
+        let frames = behind as u32 + 1;
+        self.next += self.frame * frames;
+        frames
+    }
+
+    fn sleep(&self) {
+        let now = Instant::now();
+        if self.next > now {
+            thread::sleep(self.next - now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, Chip8Error, START_PC};
+
+    #[derive(Default)]
+    struct Recorder {
+        frames: usize,
+        // Frames where the screen changed
+        presents: usize,
+        sounds: Vec<u8>,
+        polls: usize,
+        // Quits at this poll
+        quit_at: usize,
+        rewind_at: Option<usize>,
+        pause_at: Option<usize>,
+        // Key 5 is held for this poll only, the way a tap is latched
+        tap_at: Option<usize>,
+        vsync: bool,
+    }
+
+    impl VideoSink for Recorder {
+        fn present(&mut self, _screen: &Screen) -> Result<(), FrontendError> {
+            self.frames += 1;
+            self.presents += 1;
+            Ok(())
+        }
+
+        fn present_unchanged(&mut self, _screen: &Screen) -> Result<(), FrontendError> {
+            self.frames += 1;
+            Ok(())
+        }
+
+        // Without waiting, so that the loop comes round again before the next
+        // frame is due
+        fn vsync(&self) -> bool {
+            self.vsync
+        }
+    }
+
+    impl AudioSink for Recorder {
+        fn play(&mut self, sound_timer: u8, _pattern: Option<[u8; 16]>, _pitch: u8) -> Result<(), FrontendError> {
+            self.sounds.push(sound_timer);
+            Ok(())
+        }
+    }
+
+    impl InputSource for Recorder {
+        fn poll(&mut self) -> Result<Control, FrontendError> {
+            self.polls += 1;
+            if self.polls == self.quit_at {
+                Ok(Control::Quit)
+            } else if Some(self.polls) == self.rewind_at {
+                Ok(Control::Rewind)
+            } else if Some(self.polls) == self.pause_at {
+                Ok(Control::Pause)
+            } else {
+                Ok(Control::Play)
+            }
+        }
+
+        fn keypad(&self) -> [bool; 16] {
+            let mut keypad = [false; 16];
+            keypad[5] = Some(self.polls) == self.tap_at;
+            keypad
+        }
+    }
+
+    fn chip8(source: &str) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load(&assemble(source).unwrap().rom);
+        chip8
+    }
+
+    const COUNTER: &str = "
+        LD V1, 3
+        LD ST, V1
+        loop: ADD V0, 1
+        JP loop
+    ";
+
+    #[test]
+    fn test_run_until_quit() {
+        let mut chip8 = chip8(COUNTER);
+        let (mut video, mut audio) = (Recorder::default(), Recorder::default());
+        let mut input = Recorder { quit_at: 4, ..Recorder::default() };
+
+        RunLoop::new(10).run(&mut chip8, &mut video, &mut audio, &mut input).unwrap();
+
+        assert_eq!(video.frames, 3);
+        assert_eq!(audio.sounds, vec![2, 1, 0]);
+        assert_eq!(chip8.v()[0], 14);
+    }
+
+    #[test]
+    fn test_cycle_limit() {
+        let mut chip8 = chip8(COUNTER);
+        let (mut video, mut audio) = (Recorder::default(), Recorder::default());
+        let mut input = Recorder::default();
+        let mut run_loop = RunLoop::new(10);
+        run_loop.cycle_limit = Some(25);
+
+        run_loop.run(&mut chip8, &mut video, &mut audio, &mut input).unwrap();
+
+        assert_eq!(run_loop.cycles(), 25);
+        assert_eq!(video.frames, 3);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut chip8 = chip8(COUNTER);
+        let (mut video, mut audio) = (Recorder::default(), Recorder::default());
+        let mut input = Recorder { quit_at: 5, rewind_at: Some(3), ..Recorder::default() };
+        let mut run_loop = RunLoop::new(10);
+        run_loop.rewind = Some(Rewind::new(10));
+
+        run_loop.run(&mut chip8, &mut video, &mut audio, &mut input).unwrap();
+
+        // Two frames forward, one back and the second one again
+        assert_eq!(video.frames, 4);
+        assert_eq!(chip8.v()[0], 9);
+    }
+
+    #[test]
+    fn test_tap_between_frames() {
+        let mut chip8 = chip8("
+            LD V1, 5
+            loop: SKNP V1
+            LD V2, 1
+            JP loop
+        ");
+        let mut video = Recorder { vsync: true, ..Recorder::default() };
+        let mut audio = Recorder::default();
+        let mut input = Recorder { quit_at: 3, tap_at: Some(2), ..Recorder::default() };
+        let mut run_loop = RunLoop::new(10);
+        run_loop.frame = Some(Duration::from_millis(100));
+
+        // The first frame is presented without a sleep, so the loop comes
+        // round again with no frame due. The tap is polled in the next frame.
+        run_loop.run(&mut chip8, &mut video, &mut audio, &mut input).unwrap();
+
+        assert_eq!(video.frames, 2);
+        assert_eq!(chip8.v()[2], 1);
+    }
+
+    #[test]
+    fn test_rewind_between_frames() {
+        let mut chip8 = chip8(COUNTER);
+        let mut video = Recorder { vsync: true, ..Recorder::default() };
+        let mut audio = Recorder::default();
+        let mut input = Recorder { quit_at: 3, rewind_at: Some(2), ..Recorder::default() };
+        let mut run_loop = RunLoop::new(10);
+        run_loop.frame = Some(Duration::from_millis(100));
+        let mut rewind = Rewind::new(10);
+        rewind.push(&chip8);
+        run_loop.rewind = Some(rewind);
+
+        // As with a tap, the rewind is polled once there's a frame to step
+        // back instead of playing
+        run_loop.run(&mut chip8, &mut video, &mut audio, &mut input).unwrap();
+
+        assert_eq!(chip8.pc(), START_PC);
+        assert_eq!(chip8.v()[0], 0);
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut chip8 = chip8(COUNTER);
+        let (mut video, mut audio) = (Recorder::default(), Recorder::default());
+        let mut input = Recorder { quit_at: 5, pause_at: Some(2), ..Recorder::default() };
+        let mut run_loop = RunLoop::new(10);
+        run_loop.paused = true;
+
+        run_loop.run(&mut chip8, &mut video, &mut audio, &mut input).unwrap();
+
+        // Paused for the first frame, resumed at the second and playing the
+        // last two
+        assert_eq!(video.frames, 4);
+        assert_eq!(audio.sounds, vec![0, 0, 2, 1]);
+        assert_eq!(chip8.v()[0], 9);
+        assert!(!run_loop.paused);
+    }
+
+    #[test]
+    fn test_present_when_changed() {
+        // Draws in the first frame, then clears the screen in the second,
+        // fourth and sixth
+        let mut chip8 = chip8("
+            LD I, sprite
+            DRW V0, V0, 1
+            loop: ADD V0, 1
+            SE V0, 6
+            JP loop
+            CLS
+            LD V0, 0
+            JP loop
+            sprite: db 0x80
+        ");
+        let (mut video, mut audio) = (Recorder::default(), Recorder::default());
+        let mut input = Recorder { quit_at: 7, ..Recorder::default() };
+
+        RunLoop::new(10).run(&mut chip8, &mut video, &mut audio, &mut input).unwrap();
+
+        assert_eq!(video.frames, 6);
+        assert_eq!(video.presents, 4);
+    }
+
+    #[test]
+    fn test_clock() {
+        let frame = Duration::from_millis(10);
+        let mut clock = Clock::new(frame);
+        let start = clock.next;
+
+        assert_eq!(clock.due(start), 1);
+        // Early, and then on time even though the frame ran late
+        assert_eq!(clock.due(start + frame / 2), 0);
+        assert_eq!(clock.due(start + frame * 3 / 2), 1);
+        assert_eq!(clock.next, start + frame * 2);
+
+        // Catches up on the frames it missed
+        assert_eq!(clock.due(start + frame * 4), 3);
+        assert_eq!(clock.next, start + frame * 5);
+    }
+
+    #[test]
+    fn test_clock_drops_frames() {
+        let frame = Duration::from_millis(10);
+        let mut clock = Clock::new(frame);
+        let start = clock.next;
+        let stalled = start + frame * 100;
+
+        assert_eq!(clock.due(stalled), MAX_CATCH_UP);
+        assert_eq!(clock.next, stalled + frame);
+        assert_eq!(clock.due(stalled + frame), 1);
+    }
+
+    #[test]
+    fn test_exit_and_errors() {
+        let (mut video, mut audio, mut input) = (Recorder::default(), Recorder::default(), Recorder::default());
+
+        let mut exiting = chip8("EXIT");
+        RunLoop::new(10).run(&mut exiting, &mut video, &mut audio, &mut input).unwrap();
+        assert_eq!(video.frames, 1);
+
+        let mut failing = chip8("RET");
+        let result = RunLoop::new(10).run(&mut failing, &mut video, &mut audio, &mut input);
+        assert_eq!(result, Err(FrontendError::Chip8(Chip8Error::StackUnderflow)));
+    }
+}
@@ -0,0 +1,549 @@
+// A stub for the GDB remote serial protocol, so gdb or any other RSP client
+// can debug a ROM over a socket.
+//
+// The registers are sent in this order, multi-byte ones little endian:
+//
+//     number  register  bytes
+//     0-15    V0-VF     1
+//     16      I         2
+//     17      PC        2
+//     18      SP        1
+//     19      DT        1
+//     20      ST        1
+//
+// The layout is also served as target.xml through qXfer:features:read, so
+// clients don't have to be told about it.
+//
+// `c` runs until a breakpoint or a watchpoint, or until the debugger's cycle
+// limit, which is reported as a plain SIGTRAP stop. While it runs, the stub
+// keeps an eye on the stream for the 0x03 byte gdb sends to interrupt it.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::{Access, Chip8Error, Debugger, StopReason, Watch};
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// The connection to the client. While the program runs, the stub reads it
+// without waiting, to find out if the client interrupted it.
+pub trait GdbStream: Read + Write {
+    // The next byte if one has arrived, or else None straight away
+    fn try_read_byte(&mut self) -> io::Result<Option<u8>>;
+}
+
+impl GdbStream for TcpStream {
+    fn try_read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+
+        self.set_nonblocking(true)?;
+        let result = self.read(&mut byte);
+        self.set_nonblocking(false)?;
+
+        match result {
+            Ok(1) => Ok(Some(byte[0])),
+            Ok(_) => Ok(None),
+            Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+pub struct GdbStub<S> {
+    stream: S,
+    debugger: Debugger,
+}
+
+enum Reply {
+    Packet(String),
+    // Answers OK, then closes the connection
+    Detach,
+    Kill,
+}
+
+impl<S: GdbStream> GdbStub<S> {
+    pub fn new(stream: S, debugger: Debugger) -> GdbStub<S> {
+        GdbStub { stream, debugger }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+
+    pub fn into_debugger(self) -> Debugger {
+        self.debugger
+    }
+
+    // Answers packets until the client detaches, kills the program or
+    // disconnects
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            match self.handle(&packet) {
+                Reply::Packet(reply) => self.write_packet(&reply)?,
+                Reply::Detach => return self.write_packet("OK"),
+                Reply::Kill => return Ok(()),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> Reply {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.registers().iter().map(|bytes| hex(bytes)).collect(),
+            "p" => match usize::from_str_radix(args, 16).ok().and_then(|n| self.registers().get(n).cloned()) {
+                Some(bytes) => hex(&bytes),
+                None => "E00".to_string(),
+            },
+            "m" => self.read_memory(args).unwrap_or_else(|| "E01".to_string()),
+            "M" => self.write_memory(args).unwrap_or_else(|| "E01".to_string()),
+            "Z" | "z" => self.breakpoint(command == "Z", args).unwrap_or_default(),
+            "s" => stop_reply(self.debugger.step()),
+            "c" => match self.resume() {
+                Ok(reply) => reply,
+                Err(_) => return Reply::Kill,
+            },
+            "H" => "OK".to_string(),
+            "q" if args.starts_with("Supported") => "PacketSize=1000;qXfer:features:read+".to_string(),
+            "q" if args.starts_with("Xfer:features:read:") =>
+                read_features(&args["Xfer:features:read:".len()..]).unwrap_or_else(|| "E00".to_string()),
+            "q" if args == "Attached" => "1".to_string(),
+            "D" => return Reply::Detach,
+            "k" => return Reply::Kill,
+            // An empty reply tells the client the packet isn't supported
+            _ => String::new(),
+        };
+
+        Reply::Packet(reply)
+    }
+
+    // Runs until something stops the program, or the client interrupts it
+    fn resume(&mut self) -> io::Result<String> {
+        let stream = &mut self.stream;
+        let mut error = None;
+        let result = self.debugger.resume_interruptible(|| loop {
+            match stream.try_read_byte() {
+                Ok(Some(0x03)) => return true,
+                // Stray acks
+                Ok(Some(_)) => continue,
+                Ok(None) => return false,
+                Err(e) => {
+                    error = Some(e);
+                    return true;
+                },
+            }
+        });
+
+        match (error, result) {
+            (Some(error), _) => Err(error),
+            (None, Ok(StopReason::Interrupted)) => Ok(format!("S{:02x}", SIGINT)),
+            (None, result) => Ok(stop_reply(result)),
+        }
+    }
+
+    fn registers(&self) -> Vec<Vec<u8>> {
+        let chip8 = self.debugger.chip8();
+        let mut registers: Vec<Vec<u8>> = chip8.v().iter().map(|&v| vec![v]).collect();
+
+        registers.push(chip8.i().to_le_bytes().to_vec());
+        registers.push((chip8.pc() as u16).to_le_bytes().to_vec());
+        registers.push(vec![chip8.sp() as u8]);
+        registers.push(vec![chip8.delay_timer()]);
+        registers.push(vec![chip8.sound_timer]);
+
+        registers
+    }
+
+    // m addr,length
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (addr, len) = address_length(args)?;
+
+        self.debugger.chip8().memory().get(addr..(addr.checked_add(len)?)).map(hex)
+    }
+
+    // M addr,length:XX...
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let mut parts = args.splitn(2, ':');
+        let (addr, len) = address_length(parts.next()?)?;
+        let data = unhex(parts.next()?)?;
+
+        if data.len() != len {
+            return None;
+        }
+        self.debugger.chip8_mut().memory_mut().get_mut(addr..(addr.checked_add(len)?))?.copy_from_slice(&data);
+
+        Some("OK".to_string())
+    }
+
+    // Z type,addr,kind inserts and z type,addr,kind removes a breakpoint or a
+    // watchpoint. Types 0 and 1 are breakpoints, 2 to 4 are write, read and
+    // access watchpoints.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Option<String> {
+        let mut parts = args.split(',');
+        let kind = parts.next()?;
+        let addr = usize::from_str_radix(parts.next()?, 16).ok()?;
+        let length = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok()).unwrap_or(1);
+
+        let access = match kind {
+            "0" | "1" => {
+                if insert {
+                    self.debugger.add_breakpoint(addr);
+                } else {
+                    self.debugger.remove_breakpoint(addr);
+                }
+                return Some("OK".to_string());
+            },
+            "2" => Access::Write,
+            "3" => Access::Read,
+            "4" => Access::ReadWrite,
+            _ => return None,
+        };
+
+        // Watches reaching past the end of the RAM are cut short there
+        let size = self.debugger.chip8().memory().len();
+        let end = match addr.checked_add(length.max(1)) {
+            Some(end) if addr < size => end.min(size),
+            _ => return Some("E01".to_string()),
+        };
+
+        for addr in addr..end {
+            if insert {
+                self.debugger.add_watchpoint(Watch::Memory(addr), access);
+            } else {
+                self.debugger.remove_watchpoint(Watch::Memory(addr));
+            }
+        }
+
+        Some("OK".to_string())
+    }
+
+    // Returns the data of the next packet, acknowledging it, or None once the
+    // client disconnects. Acks and interrupts from the client are skipped.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => continue,
+                    None => return Ok(None),
+                }
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut sum = [0; 2];
+            for byte in sum.iter_mut() {
+                match self.read_byte()? {
+                    Some(digit) => *byte = digit,
+                    None => return Ok(None),
+                }
+            }
+
+            let expected = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if expected == Some(checksum(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+
+        match self.stream.read_exact(&mut byte) {
+            Ok(()) => Ok(Some(byte[0])),
+            Err(ref error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        write!(self.stream, "${}#{:02x}", data, checksum(data.as_bytes()))?;
+
+        self.stream.flush()
+    }
+}
+
+fn stop_reply(result: Result<StopReason, Chip8Error>) -> String {
+    match result {
+        Ok(StopReason::Watchpoint { watch: Watch::Memory(addr), access }) => {
+            let name = match access {
+                Access::Write => "watch",
+                Access::Read => "rwatch",
+                Access::ReadWrite => "awatch",
+            };
+            format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+        },
+        Ok(StopReason::Exited) => "W00".to_string(),
+        Ok(_) => format!("S{:02x}", SIGTRAP),
+        Err(Chip8Error::UnknownOpcode { .. }) => format!("S{:02x}", SIGILL),
+        Err(_) => format!("S{:02x}", SIGSEGV),
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| text.get(i..(i + 2)).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+// annex:offset,length, answered with m and a chunk of the file while there's
+// more of it, or l and the last chunk
+fn read_features(args: &str) -> Option<String> {
+    let mut parts = args.splitn(2, ':');
+    let annex = parts.next()?;
+    let (offset, len) = address_length(parts.next()?)?;
+
+    if annex != "target.xml" {
+        return None;
+    }
+    let xml = target_xml();
+    let end = offset.saturating_add(len).min(xml.len());
+    let chunk = xml.get(offset.min(end)..end)?;
+
+    Some(format!("{}{}", if end < xml.len() { 'm' } else { 'l' }, chunk))
+}
+
+// The registers in the order of the table at the top
+fn target_xml() -> String {
+    let mut xml = String::from(concat!(
+        "<?xml version=\"1.0\"?>",
+        "<!DOCTYPE target SYSTEM \"gdb-target.dtd\">",
+        "<target version=\"1.0\">",
+        "<architecture>chip8</architecture>",
+        "<feature name=\"org.chip8.core\">",
+    ));
+    for x in 0..16 {
+        xml.push_str(&format!("<reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>", x));
+    }
+    xml.push_str(concat!(
+        "<reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>",
+        "<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>",
+        "<reg name=\"sp\" bitsize=\"8\" type=\"uint8\"/>",
+        "<reg name=\"dt\" bitsize=\"8\" type=\"uint8\"/>",
+        "<reg name=\"st\" bitsize=\"8\" type=\"uint8\"/>",
+        "</feature>",
+        "</target>",
+    ));
+
+    xml
+}
+
+fn address_length(args: &str) -> Option<(usize, usize)> {
+    let mut parts = args.split(',');
+    let addr = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let len = usize::from_str_radix(parts.next()?, 16).ok()?;
+
+    Some((addr, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{assemble, Chip8};
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    // A client that sends a packet and returns the reply
+    struct Client {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    impl Client {
+        fn send(&mut self, data: &str) -> String {
+            write!(self.writer, "${}#{:02x}", data, checksum(data.as_bytes())).unwrap();
+            self.reply()
+        }
+
+        // Reads the ack and the reply to a packet that's already been sent
+        fn reply(&mut self) -> String {
+            let mut ack = [0];
+            self.reader.read_exact(&mut ack).unwrap();
+            assert_eq!(&ack, b"+");
+
+            let mut reply = Vec::new();
+            self.reader.read_until(b'#', &mut reply).unwrap();
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum).unwrap();
+            self.writer.write_all(b"+").unwrap();
+
+            assert_eq!(reply[0], b'$');
+            let reply = &reply[1..(reply.len() - 1)];
+            assert_eq!(format!("{:02x}", checksum(reply)).as_bytes(), &sum);
+            String::from_utf8(reply.to_vec()).unwrap()
+        }
+    }
+
+    // The RAM and the breakpoints, once the session ends
+    type Session = thread::JoinHandle<(Vec<u8>, Vec<usize>)>;
+
+    fn connect(source: &str) -> (Client, Session) {
+        let rom = assemble(source).unwrap().rom;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut chip8 = Chip8::new();
+            chip8.load(&rom);
+
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut stub = GdbStub::new(stream, Debugger::new(chip8));
+            stub.run().unwrap();
+            let debugger = stub.into_debugger();
+            (debugger.chip8().memory().to_vec(), debugger.breakpoints().collect())
+        });
+
+        let writer = TcpStream::connect(addr).unwrap();
+        writer.set_nodelay(true).unwrap();
+        let reader = BufReader::new(writer.try_clone().unwrap());
+        (Client { reader, writer }, server)
+    }
+
+    #[test]
+    fn test_session() {
+        let (mut client, server) = connect("
+            LD V0, 0x12     ; 0x200
+            LD I, 0x300     ; 0x202
+            LD [I], V0      ; 0x204
+            JP 0x206        ; 0x206
+        ");
+
+        assert_eq!(client.send("qSupported:swbreak+"), "PacketSize=1000;qXfer:features:read+");
+        assert_eq!(client.send("?"), "S05");
+        assert_eq!(client.send("vMustReplyEmpty"), "");
+
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p0"), "12");
+        assert_eq!(client.send("p11"), "0202");
+        assert_eq!(client.send("p15"), "E00");
+
+        assert_eq!(client.send("Z0,206,2"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        let registers = client.send("g");
+        assert_eq!(registers.len(), 23 * 2);
+        assert!(registers.starts_with("12000000"));
+        assert_eq!(&registers[32..40], "00030602");
+
+        assert_eq!(client.send("m300,2"), "1200");
+        assert_eq!(client.send("M300,2:abcd"), "OK");
+        assert_eq!(client.send("m300,2"), "abcd");
+        assert_eq!(client.send("m1000,1"), "E01");
+
+        assert_eq!(client.send("D"), "OK");
+        let (memory, breakpoints) = server.join().unwrap();
+        assert_eq!(memory[0x301], 0xCD);
+        assert_eq!(breakpoints, vec![0x206]);
+    }
+
+    #[test]
+    fn test_watchpoints_and_errors() {
+        let (mut client, server) = connect("
+            LD I, 0x300
+            LD V1, 2
+            LD [I], V1
+            RET
+        ");
+
+        assert_eq!(client.send("Z2,301,1"), "OK");
+        assert_eq!(client.send("c"), "T05watch:301;");
+        assert_eq!(client.send("z2,301,1"), "OK");
+        assert_eq!(client.send("c"), "S0b");
+
+        // Watches past the end of the RAM
+        assert_eq!(client.send("Z2,fff,10"), "OK");
+        assert_eq!(client.send("Z2,1000,1"), "E01");
+        assert_eq!(client.send("Z3,ffffffffffffffff,2"), "E01");
+
+        client.writer.write_all(b"$k#6b").unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let (mut client, server) = connect("
+            JP 0x200
+        ");
+
+        // gdb sends a bare 0x03 to stop a program that doesn't stop by itself
+        client.writer.write_all(b"$c#63\x03").unwrap();
+        assert_eq!(client.reply(), "S02");
+        assert_eq!(client.send("p11"), "0002");
+        assert_eq!(client.send("c"), "S05");
+
+        client.writer.write_all(b"$k#6b").unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_target_xml() {
+        let (mut client, server) = connect("CLS");
+
+        assert!(client.send("qSupported").contains("qXfer:features:read+"));
+
+        // Read in small chunks, the way gdb does with a small packet size
+        let mut xml = String::new();
+        loop {
+            let reply = client.send(&format!("qXfer:features:read:target.xml:{:x},40", xml.len()));
+            xml.push_str(&reply[1..]);
+            if reply.starts_with('l') {
+                break;
+            }
+            assert!(reply.starts_with('m'));
+        }
+        assert_eq!(xml, target_xml());
+        assert!(xml.contains("<architecture>chip8</architecture>"));
+        assert_eq!(xml.matches("<reg ").count(), 21);
+        let bits: usize = xml.split("bitsize=\"").skip(1)
+            .map(|rest| rest[..rest.find('"').unwrap()].parse::<usize>().unwrap())
+            .sum();
+        assert_eq!(bits, 23 * 8);
+        assert_eq!(client.send("qXfer:features:read:other.xml:0,40"), "E00");
+
+        client.writer.write_all(b"$k#6b").unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let (mut client, server) = connect("CLS");
+
+        client.writer.write_all(b"$?#00").unwrap();
+        let mut nak = [0];
+        client.reader.read_exact(&mut nak).unwrap();
+        assert_eq!(&nak, b"-");
+        assert_eq!(client.send("?"), "S05");
+
+        drop(client);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,106 @@
+// Runs a ROM without a window or an audio device, for CI and scripts.
+//
+//     headless path/to/game [--frames N | --cycles N] [--keys script]
+//                           [--screen out.png|out.pbm|out.txt] [--quirks name]
+//                           [--ips N] [--seed N] [--random xorshift|vip]
+//
+// Afterwards it prints the screen as ASCII art, unless --screen writes it to a
+// file, and then the registers. It fails if the ROM hit an error.
+
+use std::fs;
+
+use chip_8::{AudioSink, Chip8, Control, FrontendError, InputSource, KeyScript, RunLoop, Screen, VideoSink};
+
+use crate::cli::HeadlessOptions;
+use crate::{read, set_random};
+
+// Plays the key script, and quits after the last frame if there is one
+struct ScriptInput {
+    script: KeyScript,
+    frame: u64,
+    frames: Option<u64>,
+    keypad: [bool; 16],
+}
+
+impl InputSource for ScriptInput {
+    fn poll(&mut self) -> Result<Control, FrontendError> {
+        if Some(self.frame) == self.frames {
+            return Ok(Control::Quit);
+        }
+
+        self.script.apply(self.frame, &mut self.keypad);
+        self.frame += 1;
+
+        Ok(Control::Play)
+    }
+
+    fn keypad(&self) -> [bool; 16] {
+        self.keypad
+    }
+}
+
+// The screen is only looked at once the run is over, and there's no sound
+pub struct Discard;
+
+impl VideoSink for Discard {
+    fn present(&mut self, _screen: &Screen) -> Result<(), FrontendError> {
+        Ok(())
+    }
+}
+
+impl AudioSink for Discard {
+    fn play(&mut self, _sound_timer: u8, _pattern: Option<[u8; 16]>, _pitch: u8) -> Result<(), FrontendError> {
+        Ok(())
+    }
+}
+
+pub fn run(options: &HeadlessOptions) -> Result<(), String> {
+    let mut run_loop = RunLoop::new(options.instructions_per_frame());
+    run_loop.cycle_limit = options.cycles;
+    let script = match &options.keys {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+            KeyScript::parse(&text).map_err(|error| format!("{}: {}", path, error))?
+        },
+        None => KeyScript::default(),
+    };
+    let mut input = ScriptInput {
+        script,
+        frame: 0,
+        frames: options.frames,
+        keypad: [false; 16],
+    };
+
+    let data = read(&options.rom)?;
+    let mut chip8 = Chip8::with_quirks(options.quirks);
+    chip8.load(&data);
+    set_random(&mut chip8, options.random, options.seed);
+
+    let result = run_loop.run(&mut chip8, &mut Discard, &mut Discard, &mut input);
+
+    match &options.screen {
+        Some(path) => {
+            let image = if path.ends_with(".png") {
+                chip8.screen.to_png()
+            } else if path.ends_with(".pbm") {
+                chip8.screen.to_pbm().into_bytes()
+            } else {
+                chip8.screen.to_ascii().into_bytes()
+            };
+            fs::write(path, image).map_err(|error| format!("{}: {}", path, error))?;
+        },
+        None => print!("{}", chip8.screen.to_ascii()),
+    }
+
+    print_registers(&chip8);
+
+    result.map_err(|error| error.to_string())
+}
+
+fn print_registers(chip8: &Chip8) {
+    let v: Vec<String> = chip8.v().iter().map(|v| format!("{:02X}", v)).collect();
+
+    println!("V {}", v.join(" "));
+    println!("I {:04X} PC {:04X} SP {} DT {:02X} ST {:02X}",
+             chip8.i(), chip8.pc(), chip8.sp(), chip8.delay_timer(), chip8.sound_timer);
+}
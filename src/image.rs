@@ -0,0 +1,185 @@
+// Pictures of the screen, at one image pixel per CHIP-8 pixel and the current
+// resolution, for screenshots and for checking the output of ROMs in tests.
+//
+// PBM is black and white, a pixel being black when it's lit on any plane. PNG
+// is 8 bit grayscale, with the four colours of the XO-CHIP planes as black,
+// white, light and dark gray. ASCII art uses '.', '#', '+' and '@'.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use alloc::{format, vec};
+
+use crate::Screen;
+
+const GRAYS: [u8; 4] = [0x00, 0xFF, 0xAA, 0x55];
+const ASCII: [char; 4] = ['.', '#', '+', '@'];
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+// The largest block of uncompressed data deflate allows
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+impl Screen {
+    // Plain PBM, a text format
+    pub fn to_pbm(&self) -> String {
+        let mut out = format!("P1\n{} {}\n", self.width(), self.height());
+
+        for row in self.rows() {
+            let bits: Vec<&str> = row.iter().map(|&pixel| if pixel != 0 { "1" } else { "0" }).collect();
+            out.push_str(&bits.join(" "));
+            out.push('\n');
+        }
+
+        out
+    }
+
+    pub fn to_png(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(13);
+        header.extend_from_slice(&(self.width() as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height() as u32).to_be_bytes());
+        // Bit depth 8, grayscale, deflate, no filtering, no interlacing
+        header.extend_from_slice(&[8, 0, 0, 0, 0]);
+
+        // Every row starts with the type of its filter, 0 for none
+        let mut pixels = Vec::with_capacity((self.width() + 1) * self.height());
+        for row in self.rows() {
+            pixels.push(0);
+            pixels.extend(row.iter().map(|&pixel| GRAYS[(pixel & 0b11) as usize]));
+        }
+
+        let mut out = PNG_SIGNATURE.to_vec();
+        png_chunk(&mut out, b"IHDR", &header);
+        png_chunk(&mut out, b"IDAT", &zlib_stored(&pixels));
+        png_chunk(&mut out, b"IEND", &[]);
+
+        out
+    }
+
+    pub fn to_ascii(&self) -> String {
+        let mut out = String::with_capacity((self.width() + 1) * self.height());
+
+        for row in self.rows() {
+            out.extend(row.iter().map(|&pixel| ASCII[(pixel & 0b11) as usize]));
+            out.push('\n');
+        }
+
+        out
+    }
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+// A zlib stream of uncompressed deflate blocks, a screen is small enough that
+// compressing it isn't worth an encoder
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32 KiB window and the lowest compression level
+    let mut out = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(STORED_BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn screen() -> Screen {
+        let mut screen = Screen::new();
+        screen[0][0] = 1;
+        screen[0][2] = 2;
+        screen[31][63] = 3;
+        screen
+    }
+
+    #[test]
+    fn test_pbm() {
+        let pbm = screen().to_pbm();
+        let lines: Vec<&str> = pbm.lines().collect();
+
+        assert_eq!(lines.len(), 2 + 32);
+        assert_eq!(lines[0], "P1");
+        assert_eq!(lines[1], "64 32");
+        assert!(lines[2].starts_with("1 0 1 0"));
+        assert!(lines[33].ends_with("0 1"));
+    }
+
+    #[test]
+    fn test_ascii() {
+        let ascii = screen().to_ascii();
+        let lines: Vec<&str> = ascii.lines().collect();
+
+        assert_eq!(lines.len(), 32);
+        assert!(lines[0].starts_with("#.+."));
+        assert_eq!(lines[31].len(), 64);
+        assert!(lines[31].ends_with(".@"));
+    }
+
+    #[test]
+    fn test_png() {
+        let png = screen().to_png();
+
+        assert_eq!(&png[..8], &PNG_SIGNATURE);
+        // IHDR: length, type, then width and height
+        assert_eq!(&png[8..24], &[0, 0, 0, 13, b'I', b'H', b'D', b'R', 0, 0, 0, 64, 0, 0, 0, 32]);
+        assert_eq!(&png[(png.len() - 12)..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+
+        // The image data is a single stored block of 32 rows
+        let idat = &png[33..];
+        assert_eq!(&idat[4..8], b"IDAT");
+        let size = 65 * 32;
+        assert_eq!(&idat[8..15], &[0x78, 0x01, 1, (size & 0xFF) as u8, (size >> 8) as u8,
+                                   !(size & 0xFF) as u8, !(size >> 8) as u8]);
+        assert_eq!(&idat[15..19], &[0, 0xFF, 0x00, 0xAA]);
+    }
+
+    #[test]
+    fn test_checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+}
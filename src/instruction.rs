@@ -0,0 +1,341 @@
+use core::fmt;
+
+// A decoded opcode. Registers are indexes from 0 to F, and the mnemonics of
+// the `Display` implementation follow Cowgod's reference, extended for the
+// SUPER-CHIP and XO-CHIP instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    // 00CN: SCD nibble
+    ScrollDown(u8),
+    // 00DN: SCU nibble
+    ScrollUp(u8),
+    // 00E0: CLS
+    Cls,
+    // 00EE: RET
+    Ret,
+    // 00FB: SCR
+    ScrollRight,
+    // 00FC: SCL
+    ScrollLeft,
+    // 00FD: EXIT
+    Exit,
+    // 00FE: LOW
+    Low,
+    // 00FF: HIGH
+    High,
+    // 1NNN: JP addr
+    Jump(u16),
+    // 2NNN: CALL addr
+    Call(u16),
+    // 3XKK: SE Vx, byte
+    SkipEqByte(u8, u8),
+    // 4XKK: SNE Vx, byte
+    SkipNeByte(u8, u8),
+    // 5XY0: SE Vx, Vy
+    SkipEqReg(u8, u8),
+    // 5XY2: SAVE Vx, Vy
+    SaveRange(u8, u8),
+    // 5XY3: LOAD Vx, Vy
+    LoadRange(u8, u8),
+    // 6XKK: LD Vx, byte
+    LoadByte(u8, u8),
+    // 7XKK: ADD Vx, byte
+    AddByte(u8, u8),
+    // 8XY0: LD Vx, Vy
+    LoadReg(u8, u8),
+    // 8XY1: OR Vx, Vy
+    Or(u8, u8),
+    // 8XY2: AND Vx, Vy
+    And(u8, u8),
+    // 8XY3: XOR Vx, Vy
+    Xor(u8, u8),
+    // 8XY4: ADD Vx, Vy
+    AddReg(u8, u8),
+    // 8XY5: SUB Vx, Vy
+    Sub(u8, u8),
+    // 8XY6: SHR Vx, Vy
+    Shr(u8, u8),
+    // 8XY7: SUBN Vx, Vy
+    Subn(u8, u8),
+    // 8XYE: SHL Vx, Vy
+    Shl(u8, u8),
+    // 9XY0: SNE Vx, Vy
+    SkipNeReg(u8, u8),
+    // ANNN: LD I, addr
+    LoadI(u16),
+    // BNNN: JP V0, addr
+    JumpV0(u16),
+    // CXKK: RND Vx, byte
+    Random(u8, u8),
+    // DXYN: DRW Vx, Vy, nibble
+    Draw(u8, u8, u8),
+    // EX9E: SKP Vx
+    SkipKey(u8),
+    // EXA1: SKNP Vx
+    SkipNotKey(u8),
+    // F000 NNNN: LD I, long addr. The address is the opcode after this one.
+    LoadLongI,
+    // FN01: PLANE nibble
+    Plane(u8),
+    // F002: AUDIO
+    Audio,
+    // FX07: LD Vx, DT
+    LoadDelay(u8),
+    // FX0A: LD Vx, K
+    WaitKey(u8),
+    // FX15: LD DT, Vx
+    SetDelay(u8),
+    // FX18: LD ST, Vx
+    SetSound(u8),
+    // FX1E: ADD I, Vx
+    AddI(u8),
+    // FX29: LD F, Vx
+    LoadFont(u8),
+    // FX30: LD HF, Vx
+    LoadBigFont(u8),
+    // FX33: LD B, Vx
+    Bcd(u8),
+    // FX3A: PITCH Vx
+    Pitch(u8),
+    // FX55: LD [I], Vx
+    Store(u8),
+    // FX65: LD Vx, [I]
+    Load(u8),
+    // FX75: LD R, Vx
+    StoreFlags(u8),
+    // FX85: LD Vx, R
+    LoadFlags(u8),
+}
+
+impl Instruction {
+    // None if the opcode isn't any instruction
+    pub fn decode(opcode: u16) -> Option<Instruction> {
+        // These are the nibbles (groups of 4 bits)
+        let op = ((opcode & 0xF000) >> 12) as u8;
+        let x = ((opcode & 0x0F00) >> 8) as u8;
+        let y = ((opcode & 0x00F0) >> 4) as u8;
+        let n = (opcode & 0x000F) as u8;
+
+        let nnn = opcode & 0x0FFF;
+        let kk = (opcode & 0x00FF) as u8;
+
+        let instruction = match (op, x, y, n) {
+            (0x00, 0x00, 0x0c,    _) => Instruction::ScrollDown(n),
+            (0x00, 0x00, 0x0d,    _) => Instruction::ScrollUp(n),
+            (0x00, 0x00, 0x0e, 0x00) => Instruction::Cls,
+            (0x00, 0x00, 0x0e, 0x0e) => Instruction::Ret,
+            (0x00, 0x00, 0x0f, 0x0b) => Instruction::ScrollRight,
+            (0x00, 0x00, 0x0f, 0x0c) => Instruction::ScrollLeft,
+            (0x00, 0x00, 0x0f, 0x0d) => Instruction::Exit,
+            (0x00, 0x00, 0x0f, 0x0e) => Instruction::Low,
+            (0x00, 0x00, 0x0f, 0x0f) => Instruction::High,
+            (0x01,    _,    _,    _) => Instruction::Jump(nnn),
+            (0x02,    _,    _,    _) => Instruction::Call(nnn),
+            (0x03,    _,    _,    _) => Instruction::SkipEqByte(x, kk),
+            (0x04,    _,    _,    _) => Instruction::SkipNeByte(x, kk),
+            (0x05,    _,    _, 0x00) => Instruction::SkipEqReg(x, y),
+            (0x05,    _,    _, 0x02) => Instruction::SaveRange(x, y),
+            (0x05,    _,    _, 0x03) => Instruction::LoadRange(x, y),
+            (0x06,    _,    _,    _) => Instruction::LoadByte(x, kk),
+            (0x07,    _,    _,    _) => Instruction::AddByte(x, kk),
+            (0x08,    _,    _, 0x00) => Instruction::LoadReg(x, y),
+            (0x08,    _,    _, 0x01) => Instruction::Or(x, y),
+            (0x08,    _,    _, 0x02) => Instruction::And(x, y),
+            (0x08,    _,    _, 0x03) => Instruction::Xor(x, y),
+            (0x08,    _,    _, 0x04) => Instruction::AddReg(x, y),
+            (0x08,    _,    _, 0x05) => Instruction::Sub(x, y),
+            (0x08,    _,    _, 0x06) => Instruction::Shr(x, y),
+            (0x08,    _,    _, 0x07) => Instruction::Subn(x, y),
+            (0x08,    _,    _, 0x0e) => Instruction::Shl(x, y),
+            (0x09,    _,    _, 0x00) => Instruction::SkipNeReg(x, y),
+            (0x0a,    _,    _,    _) => Instruction::LoadI(nnn),
+            (0x0b,    _,    _,    _) => Instruction::JumpV0(nnn),
+            (0x0c,    _,    _,    _) => Instruction::Random(x, kk),
+            (0x0d,    _,    _,    _) => Instruction::Draw(x, y, n),
+            (0x0e,    _, 0x09, 0x0e) => Instruction::SkipKey(x),
+            (0x0e,    _, 0x0a, 0x01) => Instruction::SkipNotKey(x),
+            (0x0f, 0x00, 0x00, 0x00) => Instruction::LoadLongI,
+            (0x0f,    _, 0x00, 0x01) => Instruction::Plane(x),
+            (0x0f, 0x00, 0x00, 0x02) => Instruction::Audio,
+            (0x0f,    _, 0x00, 0x07) => Instruction::LoadDelay(x),
+            (0x0f,    _, 0x00, 0x0a) => Instruction::WaitKey(x),
+            (0x0f,    _, 0x01, 0x05) => Instruction::SetDelay(x),
+            (0x0f,    _, 0x01, 0x08) => Instruction::SetSound(x),
+            (0x0f,    _, 0x01, 0x0e) => Instruction::AddI(x),
+            (0x0f,    _, 0x02, 0x09) => Instruction::LoadFont(x),
+            (0x0f,    _, 0x03, 0x00) => Instruction::LoadBigFont(x),
+            (0x0f,    _, 0x03, 0x03) => Instruction::Bcd(x),
+            (0x0f,    _, 0x03, 0x0a) => Instruction::Pitch(x),
+            (0x0f,    _, 0x05, 0x05) => Instruction::Store(x),
+            (0x0f,    _, 0x06, 0x05) => Instruction::Load(x),
+            (0x0f,    _, 0x07, 0x05) => Instruction::StoreFlags(x),
+            (0x0f,    _, 0x08, 0x05) => Instruction::LoadFlags(x),
+            _                        => return None,
+        };
+
+        Some(instruction)
+    }
+
+    // The opcode of the instruction, out of range operands are truncated
+    pub fn encode(&self) -> u16 {
+        let xy = |op: u16, x: u8, y: u8, n: u16| {
+            op << 12 | ((x as u16) & 0xF) << 8 | ((y as u16) & 0xF) << 4 | n
+        };
+        let xkk = |op: u16, x: u8, kk: u8| op << 12 | ((x as u16) & 0xF) << 8 | kk as u16;
+        let nnn = |op: u16, nnn: u16| op << 12 | (nnn & 0xFFF);
+
+        match *self {
+            Instruction::ScrollDown(n) => 0x00C0 | ((n as u16) & 0xF),
+            Instruction::ScrollUp(n) => 0x00D0 | ((n as u16) & 0xF),
+            Instruction::Cls => 0x00E0,
+            Instruction::Ret => 0x00EE,
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::Low => 0x00FE,
+            Instruction::High => 0x00FF,
+            Instruction::Jump(addr) => nnn(0x1, addr),
+            Instruction::Call(addr) => nnn(0x2, addr),
+            Instruction::SkipEqByte(x, kk) => xkk(0x3, x, kk),
+            Instruction::SkipNeByte(x, kk) => xkk(0x4, x, kk),
+            Instruction::SkipEqReg(x, y) => xy(0x5, x, y, 0x0),
+            Instruction::SaveRange(x, y) => xy(0x5, x, y, 0x2),
+            Instruction::LoadRange(x, y) => xy(0x5, x, y, 0x3),
+            Instruction::LoadByte(x, kk) => xkk(0x6, x, kk),
+            Instruction::AddByte(x, kk) => xkk(0x7, x, kk),
+            Instruction::LoadReg(x, y) => xy(0x8, x, y, 0x0),
+            Instruction::Or(x, y) => xy(0x8, x, y, 0x1),
+            Instruction::And(x, y) => xy(0x8, x, y, 0x2),
+            Instruction::Xor(x, y) => xy(0x8, x, y, 0x3),
+            Instruction::AddReg(x, y) => xy(0x8, x, y, 0x4),
+            Instruction::Sub(x, y) => xy(0x8, x, y, 0x5),
+            Instruction::Shr(x, y) => xy(0x8, x, y, 0x6),
+            Instruction::Subn(x, y) => xy(0x8, x, y, 0x7),
+            Instruction::Shl(x, y) => xy(0x8, x, y, 0xE),
+            Instruction::SkipNeReg(x, y) => xy(0x9, x, y, 0x0),
+            Instruction::LoadI(addr) => nnn(0xA, addr),
+            Instruction::JumpV0(addr) => nnn(0xB, addr),
+            Instruction::Random(x, kk) => xkk(0xC, x, kk),
+            Instruction::Draw(x, y, n) => xy(0xD, x, y, (n as u16) & 0xF),
+            Instruction::SkipKey(x) => xkk(0xE, x, 0x9E),
+            Instruction::SkipNotKey(x) => xkk(0xE, x, 0xA1),
+            Instruction::LoadLongI => 0xF000,
+            Instruction::Plane(n) => xkk(0xF, n, 0x01),
+            Instruction::Audio => 0xF002,
+            Instruction::LoadDelay(x) => xkk(0xF, x, 0x07),
+            Instruction::WaitKey(x) => xkk(0xF, x, 0x0A),
+            Instruction::SetDelay(x) => xkk(0xF, x, 0x15),
+            Instruction::SetSound(x) => xkk(0xF, x, 0x18),
+            Instruction::AddI(x) => xkk(0xF, x, 0x1E),
+            Instruction::LoadFont(x) => xkk(0xF, x, 0x29),
+            Instruction::LoadBigFont(x) => xkk(0xF, x, 0x30),
+            Instruction::Bcd(x) => xkk(0xF, x, 0x33),
+            Instruction::Pitch(x) => xkk(0xF, x, 0x3A),
+            Instruction::Store(x) => xkk(0xF, x, 0x55),
+            Instruction::Load(x) => xkk(0xF, x, 0x65),
+            Instruction::StoreFlags(x) => xkk(0xF, x, 0x75),
+            Instruction::LoadFlags(x) => xkk(0xF, x, 0x85),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::ScrollDown(n) => write!(f, "SCD {}", n),
+            Instruction::ScrollUp(n) => write!(f, "SCU {}", n),
+            Instruction::Cls => write!(f, "CLS"),
+            Instruction::Ret => write!(f, "RET"),
+            Instruction::ScrollRight => write!(f, "SCR"),
+            Instruction::ScrollLeft => write!(f, "SCL"),
+            Instruction::Exit => write!(f, "EXIT"),
+            Instruction::Low => write!(f, "LOW"),
+            Instruction::High => write!(f, "HIGH"),
+            Instruction::Jump(nnn) => write!(f, "JP 0x{:03X}", nnn),
+            Instruction::Call(nnn) => write!(f, "CALL 0x{:03X}", nnn),
+            Instruction::SkipEqByte(x, kk) => write!(f, "SE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SkipNeByte(x, kk) => write!(f, "SNE V{:X}, 0x{:02X}", x, kk),
+            Instruction::SkipEqReg(x, y) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Instruction::SaveRange(x, y) => write!(f, "SAVE V{:X}, V{:X}", x, y),
+            Instruction::LoadRange(x, y) => write!(f, "LOAD V{:X}, V{:X}", x, y),
+            Instruction::LoadByte(x, kk) => write!(f, "LD V{:X}, 0x{:02X}", x, kk),
+            Instruction::AddByte(x, kk) => write!(f, "ADD V{:X}, 0x{:02X}", x, kk),
+            Instruction::LoadReg(x, y) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Instruction::Or(x, y) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Instruction::And(x, y) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Instruction::Xor(x, y) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Instruction::AddReg(x, y) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Instruction::Sub(x, y) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Instruction::Shr(x, y) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Instruction::Subn(x, y) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Instruction::Shl(x, y) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Instruction::SkipNeReg(x, y) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Instruction::LoadI(nnn) => write!(f, "LD I, 0x{:03X}", nnn),
+            Instruction::JumpV0(nnn) => write!(f, "JP V0, 0x{:03X}", nnn),
+            Instruction::Random(x, kk) => write!(f, "RND V{:X}, 0x{:02X}", x, kk),
+            Instruction::Draw(x, y, n) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, n),
+            Instruction::SkipKey(x) => write!(f, "SKP V{:X}", x),
+            Instruction::SkipNotKey(x) => write!(f, "SKNP V{:X}", x),
+            Instruction::LoadLongI => write!(f, "LD I, long"),
+            Instruction::Plane(n) => write!(f, "PLANE {}", n),
+            Instruction::Audio => write!(f, "AUDIO"),
+            Instruction::LoadDelay(x) => write!(f, "LD V{:X}, DT", x),
+            Instruction::WaitKey(x) => write!(f, "LD V{:X}, K", x),
+            Instruction::SetDelay(x) => write!(f, "LD DT, V{:X}", x),
+            Instruction::SetSound(x) => write!(f, "LD ST, V{:X}", x),
+            Instruction::AddI(x) => write!(f, "ADD I, V{:X}", x),
+            Instruction::LoadFont(x) => write!(f, "LD F, V{:X}", x),
+            Instruction::LoadBigFont(x) => write!(f, "LD HF, V{:X}", x),
+            Instruction::Bcd(x) => write!(f, "LD B, V{:X}", x),
+            Instruction::Pitch(x) => write!(f, "PITCH V{:X}", x),
+            Instruction::Store(x) => write!(f, "LD [I], V{:X}", x),
+            Instruction::Load(x) => write!(f, "LD V{:X}, [I]", x),
+            Instruction::StoreFlags(x) => write!(f, "LD R, V{:X}", x),
+            Instruction::LoadFlags(x) => write!(f, "LD V{:X}, R", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Instruction::decode(0x00E0), Some(Instruction::Cls));
+        assert_eq!(Instruction::decode(0x2F4C), Some(Instruction::Call(0xF4C)));
+        assert_eq!(Instruction::decode(0x651F), Some(Instruction::LoadByte(5, 0x1F)));
+        assert_eq!(Instruction::decode(0x8AB6), Some(Instruction::Shr(0xA, 0xB)));
+        assert_eq!(Instruction::decode(0xD013), Some(Instruction::Draw(0, 1, 3)));
+        assert_eq!(Instruction::decode(0xF000), Some(Instruction::LoadLongI));
+        assert_eq!(Instruction::decode(0xF201), Some(Instruction::Plane(2)));
+    }
+
+    #[test]
+    fn test_decode_unknown() {
+        assert_eq!(Instruction::decode(0x0123), None);
+        assert_eq!(Instruction::decode(0x5AB1), None);
+        assert_eq!(Instruction::decode(0x800F), None);
+        assert_eq!(Instruction::decode(0xFFFF), None);
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        for opcode in 0..=0xFFFF {
+            if let Some(instruction) = Instruction::decode(opcode) {
+                assert_eq!(instruction.encode(), opcode, "{}", instruction);
+            }
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        let text = |opcode| Instruction::decode(opcode).unwrap().to_string();
+
+        assert_eq!(text(0x651F), "LD V5, 0x1F");
+        assert_eq!(text(0xD013), "DRW V0, V1, 3");
+        assert_eq!(text(0x1228), "JP 0x228");
+        assert_eq!(text(0xFA65), "LD VA, [I]");
+        assert_eq!(text(0x00C4), "SCD 4");
+    }
+}
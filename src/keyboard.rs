@@ -1,47 +1,95 @@
+// The keypad read from SDL key events. The events are kept between frames, so
+// a key tapped faster than a frame still counts as held for the frame it was
+// tapped in.
+
+use std::collections::HashSet;
+
 use sdl2::EventPump;
+use sdl2::event::{Event, WindowEvent};
 use sdl2::keyboard::Scancode;
 
-pub fn poll(event_pump: &EventPump) -> [bool; 16] {
-    let mut keys = [false; 16];
-
-    let keyboard: Vec<Scancode> = event_pump
-        .keyboard_state()
-        .pressed_scancodes()
-        // Scancodes represent the physical position of the key, modeled
-        // after the standard QWERTY keyboard.
-        // On an AZERTY keyboard, pressing the QWERTY letter 'Q' will emit
-        // a 'q' scancode and an 'a' keycode.
-        .collect();
-
-    // | 1 | 2 | 3 | 4 |    | 1 | 2 | 3 | C |
-    // | Q | W | E | R | -> | 4 | 5 | 6 | D |
-    // | A | S | D | F |    | 7 | 8 | 9 | E |
-    // | Z | X | C | V |    | A | 0 | B | F |
-    for pressed_key in keyboard {
-        let index = match pressed_key {
-            Scancode::Num1 => Some(0x1),
-            Scancode::Num2 => Some(0x2),
-            Scancode::Num3 => Some(0x3),
-            Scancode::Num4 => Some(0xC),
-            Scancode::Q    => Some(0x4),
-            Scancode::W    => Some(0x5),
-            Scancode::E    => Some(0x6),
-            Scancode::R    => Some(0xD),
-            Scancode::A    => Some(0x7),
-            Scancode::S    => Some(0x8),
-            Scancode::D    => Some(0x9),
-            Scancode::F    => Some(0xE),
-            Scancode::Z    => Some(0xA),
-            Scancode::X    => Some(0x0),
-            Scancode::C    => Some(0xB),
-            Scancode::V    => Some(0xF),
-            _ => None,
-        };
-
-        if let Some(i) = index {
-            keys[i] = true;
+use chip_8::{Control, FrontendError, InputSource};
+
+use crate::keymap::Keymap;
+
+pub struct Keyboard {
+    event_pump: EventPump,
+    // The host keys of each keypad key
+    scancodes: Vec<Vec<Scancode>>,
+    pause: Vec<Scancode>,
+    rewind: Vec<Scancode>,
+    // The host keys down right now
+    down: HashSet<Scancode>,
+    // The keypad keys pressed since the last poll, even if they were already
+    // released
+    pressed: [bool; 16],
+}
+
+impl Keyboard {
+    pub fn new(event_pump: EventPump, keymap: &Keymap) -> Result<Keyboard, String> {
+        Ok(Keyboard {
+            event_pump,
+            scancodes: keymap.keys().map(scancodes).collect::<Result<_, _>>()?,
+            pause: scancodes(keymap.pause())?,
+            rewind: scancodes(keymap.rewind())?,
+            down: HashSet::new(),
+            pressed: [false; 16],
+        })
+    }
+}
+
+fn scancodes(names: &[String]) -> Result<Vec<Scancode>, String> {
+    names.iter()
+        .map(|name| Scancode::from_name(name).ok_or_else(|| format!("Unknown key {}", name)))
+        .collect()
+}
+
+impl InputSource for Keyboard {
+    // The pause keys pause, the rewind keys rewind while they're held down
+    fn poll(&mut self) -> Result<Control, FrontendError> {
+        let mut pause = false;
+        self.pressed = [false; 16];
+
+        for event in self.event_pump.poll_iter() {
+            match event {
+                Event::Quit {..} => return Ok(Control::Quit),
+                Event::KeyDown { scancode: Some(scancode), repeat: false, .. } if self.pause.contains(&scancode) =>
+                    pause = true,
+                // Scancodes represent the physical position of the key, modeled
+                // after the standard QWERTY keyboard.
+                // On an AZERTY keyboard, pressing the QWERTY letter 'Q' will emit
+                // a 'q' scancode and an 'a' keycode.
+                Event::KeyDown { scancode: Some(scancode), .. } => {
+                    self.down.insert(scancode);
+                    for (pressed, scancodes) in self.pressed.iter_mut().zip(self.scancodes.iter()) {
+                        *pressed |= scancodes.contains(&scancode);
+                    }
+                },
+                Event::KeyUp { scancode: Some(scancode), .. } => {
+                    self.down.remove(&scancode);
+                },
+                // The key ups go to whichever window has the focus now
+                Event::Window { win_event: WindowEvent::FocusLost, .. } => self.down.clear(),
+                _ => {},
+            }
+        }
+
+        if pause {
+            Ok(Control::Pause)
+        } else if self.rewind.iter().any(|scancode| self.down.contains(scancode)) {
+            // Held down to step the game backwards
+            Ok(Control::Rewind)
+        } else {
+            Ok(Control::Play)
         }
     }
 
-    keys
+    // The keys held down, and the ones tapped since the last frame
+    fn keypad(&self) -> [bool; 16] {
+        let mut keypad = self.pressed;
+        for (held, scancodes) in keypad.iter_mut().zip(self.scancodes.iter()) {
+            *held |= scancodes.iter().any(|scancode| self.down.contains(scancode));
+        }
+        keypad
+    }
 }
@@ -0,0 +1,295 @@
+// Which host keys press which keypad key. Keys are named the way SDL names
+// scancodes, "Q", "1", "Up" or "Keypad 7" for instance.
+//
+// A keymap is a TOML file. It can start from one of the presets, and then give
+// keypad keys one host key or a list of them. Tables under `roms` change the
+// layout for a single ROM, by file name, on top of the rest of the file:
+//
+//     preset = "qwerty"
+//     5 = ["W", "Up"]
+//     8 = ["S", "Down"]
+//
+//     [roms."tetris.ch8"]
+//     4 = "Left"
+//     6 = "Right"
+//
+// The presets are `qwerty`, the default, with the keypad on the 4x4 block of
+// keys under 1, `numpad`, with the keypad digits on the numeric keypad, and
+// `cosmac`, with every key of the COSMAC VIP hex keypad on the key of the same
+// digit or letter.
+//
+// `pause` and `rewind` take the keys that pause the game and rewind it, Space
+// and Backspace by default. They can't also be keypad keys.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use toml::Value;
+
+// | 1 | 2 | 3 | 4 |    | 1 | 2 | 3 | C |
+// | Q | W | E | R | -> | 4 | 5 | 6 | D |
+// | A | S | D | F |    | 7 | 8 | 9 | E |
+// | Z | X | C | V |    | A | 0 | B | F |
+const QWERTY: [&str; 16] = ["X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Z", "C", "4", "R", "F", "V"];
+
+// The digits on their keys, and A to F on the keys around them
+const NUMPAD: [&str; 16] = [
+    "Keypad 0", "Keypad 1", "Keypad 2", "Keypad 3", "Keypad 4", "Keypad 5", "Keypad 6", "Keypad 7",
+    "Keypad 8", "Keypad 9", "Keypad /", "Keypad *", "Keypad -", "Keypad +", "Keypad Enter", "Keypad .",
+];
+
+const COSMAC: [&str; 16] = ["0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "A", "B", "C", "D", "E", "F"];
+
+pub struct Keymap {
+    // The host keys of each keypad key
+    keys: Vec<Vec<String>>,
+    pause: Vec<String>,
+    rewind: Vec<String>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::preset(&QWERTY)
+    }
+}
+
+impl Keymap {
+    // A preset by name, or else the keymap file at `path`, for the ROM `rom`
+    pub fn load(path: &str, rom: &str) -> Result<Keymap, String> {
+        if let Some(keys) = preset(path) {
+            return Ok(Keymap::preset(keys));
+        }
+
+        let text = fs::read_to_string(path).map_err(|error| format!("{}: {}", path, error))?;
+        Keymap::parse(&text, rom).map_err(|error| format!("{}: {}", path, error))
+    }
+
+    // The keymap file in the user's configuration directory, if there is one
+    pub fn load_config(rom: &str) -> Result<Keymap, String> {
+        match config_path() {
+            Some(path) if path.exists() => Keymap::load(&path.to_string_lossy(), rom),
+            _ => Ok(Keymap::default()),
+        }
+    }
+
+    pub fn parse(text: &str, rom: &str) -> Result<Keymap, String> {
+        let root: toml::Table = text.parse().map_err(|error: toml::de::Error| error.to_string())?;
+        let mut keymap = Keymap::default();
+
+        keymap.apply(&root)?;
+
+        let name = Path::new(rom).file_name().map(|name| name.to_string_lossy());
+        let roms = match root.get("roms") {
+            Some(Value::Table(roms)) => Some(roms),
+            Some(_) => return Err("roms should be a table of ROM file names".to_string()),
+            None => None,
+        };
+        if let (Some(roms), Some(name)) = (roms, name) {
+            match roms.get(name.as_ref()) {
+                Some(Value::Table(layout)) => keymap.apply(layout)?,
+                Some(_) => return Err(format!("roms.\"{}\" should be a table", name)),
+                None => {},
+            }
+        }
+
+        for (control, names) in [("pause", &keymap.pause), ("rewind", &keymap.rewind)] {
+            for name in names {
+                let bound = |names: &Vec<String>| names.iter().any(|other| other.eq_ignore_ascii_case(name));
+                if let Some(key) = keymap.keys.iter().position(bound) {
+                    return Err(format!("{} is bound to both {} and keypad key {:X}", name, control, key));
+                }
+            }
+        }
+
+        Ok(keymap)
+    }
+
+    // The names of the host keys for each keypad key
+    pub fn keys(&self) -> impl Iterator<Item = &[String]> {
+        self.keys.iter().map(Vec::as_slice)
+    }
+
+    // The names of the host keys that pause the game
+    pub fn pause(&self) -> &[String] {
+        &self.pause
+    }
+
+    // The names of the host keys that rewind the game while they're held
+    pub fn rewind(&self) -> &[String] {
+        &self.rewind
+    }
+
+    fn preset(names: &[&str; 16]) -> Keymap {
+        Keymap {
+            keys: names.iter().map(|name| vec![name.to_string()]).collect(),
+            pause: vec!["Space".to_string()],
+            rewind: vec!["Backspace".to_string()],
+        }
+    }
+
+    // Changes the layout to the preset and the keys in a table. A preset
+    // keeps the pause and rewind keys.
+    fn apply(&mut self, layout: &toml::Table) -> Result<(), String> {
+        match layout.get("preset") {
+            Some(Value::String(name)) => {
+                let keys = preset(name).ok_or_else(|| {
+                    format!("Unknown preset {}, expected qwerty, numpad or cosmac", name)
+                })?;
+                self.keys = Keymap::preset(keys).keys;
+            },
+            Some(_) => return Err("preset should be a name".to_string()),
+            None => {},
+        }
+
+        for (key, value) in layout {
+            match key.as_str() {
+                "preset" | "roms" => {},
+                "pause" => self.pause = names(key, value)?,
+                "rewind" => self.rewind = names(key, value)?,
+                _ => {
+                    let index = u8::from_str_radix(key, 16).ok().filter(|&index| index < 16)
+                        .ok_or_else(|| format!("{} isn't a keypad key, expected 0 to F", key))?;
+                    self.keys[index as usize] = names(key, value)?;
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// A host key name, or a list of them
+fn names(key: &str, value: &Value) -> Result<Vec<String>, String> {
+    match value {
+        Value::String(name) => Ok(vec![name.clone()]),
+        Value::Array(names) => names.iter()
+            .map(|name| name.as_str().map(str::to_string))
+            .collect::<Option<_>>()
+            .ok_or_else(|| format!("The keys for {} should be names", key)),
+        _ => Err(format!("The keys for {} should be a name or a list of names", key)),
+    }
+}
+
+fn preset(name: &str) -> Option<&'static [&'static str; 16]> {
+    match name {
+        "qwerty" => Some(&QWERTY),
+        "numpad" => Some(&NUMPAD),
+        "cosmac" => Some(&COSMAC),
+        _ => None,
+    }
+}
+
+// $XDG_CONFIG_HOME/chip-8/keymap.toml, or ~/.config/chip-8/keymap.toml
+fn config_path() -> Option<PathBuf> {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+
+    Some(config.join("chip-8").join("keymap.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(keymap: &Keymap, key: usize) -> Vec<&str> {
+        keymap.keys().nth(key).unwrap().iter().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn test_default() {
+        let keymap = Keymap::parse("", "game.ch8").unwrap();
+
+        assert_eq!(keymap.keys().count(), 16);
+        assert_eq!(key(&keymap, 0x0), ["X"]);
+        assert_eq!(key(&keymap, 0x1), ["1"]);
+        assert_eq!(key(&keymap, 0xC), ["4"]);
+        assert_eq!(key(&keymap, 0xF), ["V"]);
+    }
+
+    #[test]
+    fn test_presets() {
+        let numpad = Keymap::parse("preset = \"numpad\"", "game.ch8").unwrap();
+        assert_eq!(key(&numpad, 7), ["Keypad 7"]);
+        assert_eq!(key(&numpad, 0xE), ["Keypad Enter"]);
+
+        let cosmac = Keymap::load("cosmac", "game.ch8").unwrap();
+        assert_eq!(key(&cosmac, 0xA), ["A"]);
+        assert_eq!(key(&cosmac, 3), ["3"]);
+
+        let qwerty = Keymap::load("qwerty", "game.ch8").unwrap();
+        assert_eq!(key(&qwerty, 5), ["W"]);
+    }
+
+    #[test]
+    fn test_keys() {
+        let keymap = Keymap::parse("
+            preset = \"cosmac\"
+            5 = [\"W\", \"Up\"]
+            a = \"Return\"
+        ", "game.ch8").unwrap();
+
+        assert_eq!(key(&keymap, 5), ["W", "Up"]);
+        assert_eq!(key(&keymap, 0xA), ["Return"]);
+        assert_eq!(key(&keymap, 0xB), ["B"]);
+    }
+
+    #[test]
+    fn test_rom_overrides() {
+        let text = "
+            4 = \"A\"
+            6 = \"D\"
+
+            [roms.\"tetris.ch8\"]
+            preset = \"numpad\"
+            4 = \"Left\"
+        ";
+
+        // Only the ROM of the same file name, wherever it is
+        let tetris = Keymap::parse(text, "roms/tetris.ch8").unwrap();
+        assert_eq!(key(&tetris, 4), ["Left"]);
+        assert_eq!(key(&tetris, 6), ["Keypad 6"]);
+
+        let other = Keymap::parse(text, "roms/pong.ch8").unwrap();
+        assert_eq!(key(&other, 4), ["A"]);
+        assert_eq!(key(&other, 6), ["D"]);
+    }
+
+    #[test]
+    fn test_pause_and_rewind() {
+        let keymap = Keymap::parse("", "game.ch8").unwrap();
+        assert_eq!(keymap.pause(), ["Space"]);
+        assert_eq!(keymap.rewind(), ["Backspace"]);
+
+        let keymap = Keymap::parse("
+            pause = \"P\"
+            rewind = [\"Left\", \"Keypad 0\"]
+
+            [roms.\"game.ch8\"]
+            preset = \"cosmac\"
+        ", "game.ch8").unwrap();
+        assert_eq!(keymap.pause(), ["P"]);
+        assert_eq!(keymap.rewind(), ["Left", "Keypad 0"]);
+        assert_eq!(key(&keymap, 0xC), ["C"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text: &str| Keymap::parse(text, "game.ch8").err().unwrap();
+
+        assert_eq!(error("G = \"Q\""), "G isn't a keypad key, expected 0 to F");
+        assert_eq!(error("10 = \"Q\""), "10 isn't a keypad key, expected 0 to F");
+        assert_eq!(error("preset = \"azerty\""), "Unknown preset azerty, expected qwerty, numpad or cosmac");
+        assert_eq!(error("preset = 1"), "preset should be a name");
+        assert_eq!(error("1 = 2"), "The keys for 1 should be a name or a list of names");
+        assert_eq!(error("1 = [\"Q\", 2]"), "The keys for 1 should be names");
+        assert_eq!(error("roms = 1"), "roms should be a table of ROM file names");
+        assert_eq!(error("[roms]\n\"game.ch8\" = 1"), "roms.\"game.ch8\" should be a table");
+        assert_eq!(error("[roms.\"game.ch8\"]\nZ = \"Q\""), "Z isn't a keypad key, expected 0 to F");
+        assert!(error("1 = ").contains("line 1"));
+        assert_eq!(error("7 = \"Space\""), "Space is bound to both pause and keypad key 7");
+        assert_eq!(error("rewind = \"q\""), "q is bound to both rewind and keypad key 4");
+        assert_eq!(error("pause = 1"), "The keys for pause should be a name or a list of names");
+    }
+}
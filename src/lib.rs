@@ -1,11 +1,67 @@
-use rand;
+// Without the `std` feature the VM only needs an allocator, so it can run on
+// a microcontroller
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cmp;
+
+mod assembler;
+#[cfg(feature = "std")]
+mod dap;
+mod debugger;
+mod disasm;
+mod error;
+#[cfg(feature = "std")]
+mod frontend;
+#[cfg(feature = "std")]
+mod gdb;
+mod image;
+mod instruction;
+mod quirks;
+mod random;
+mod rewind;
+mod screen;
+mod script;
+mod state;
+#[cfg(feature = "std")]
+mod trace;
+
+pub use assembler::{assemble, Assembly};
+#[cfg(feature = "std")]
+pub use dap::DapServer;
+pub use debugger::{Access, Debugger, StopReason, Watch};
+pub use disasm::{disassemble, DisasmLine};
+pub use error::{AsmError, Chip8Error, ScriptError, StateError};
+#[cfg(feature = "std")]
+pub use error::FrontendError;
+#[cfg(feature = "std")]
+pub use frontend::{AudioSink, Control, InputSource, RunLoop, VideoSink};
+#[cfg(feature = "std")]
+pub use gdb::{GdbStream, GdbStub};
+pub use instruction::Instruction;
+pub use quirks::Quirks;
+pub use random::{CosmacVipRandom, RandomSource, XorShift};
+pub use rewind::Rewind;
+pub use screen::Screen;
+pub use script::{KeyEvent, KeyScript};
+#[cfg(feature = "std")]
+pub use trace::Tracer;
 
 pub const CHIP8_WIDTH: usize = 64;
 pub const CHIP8_HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
 pub const CHIP8_RAM: usize = 4096;
+pub const XOCHIP_RAM: usize = 65536;
 
-const START_PC: usize = 0x200;
+// Where ROMs are loaded in memory
+pub const START_PC: usize = 0x200;
 const OPCODE_SIZE: usize = 2;
+const BIG_FONT_ADDR: usize = 0x50;
 
 enum ProgramCounter {
     Next,
@@ -13,6 +69,15 @@ enum ProgramCounter {
     Jump(u16),
 }
 
+// What the VM is doing after a call to `Chip8::cycle`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepOutcome {
+    Running,
+    WaitingForKey,
+    // The program ran the SUPER-CHIP 00FD instruction
+    Exited,
+}
+
 impl ProgramCounter {
     fn skip_if(condition: bool) -> ProgramCounter {
         if condition {
@@ -35,23 +100,50 @@ pub struct Chip8 {
     // Stack, used for recursion.
     stack: [u16; 16],
     // The RAM
-    memory: [u8; CHIP8_RAM],
-    pub screen: [[u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
+    memory: Vec<u8>,
+    pub screen: Screen,
+    // Bumped whenever an instruction changes the screen
+    screen_generation: u64,
     pub keypad: [bool; 16],
     pub sound_timer: u8,
     delay_timer: u8,
 
     keypad_waiting: bool,
     keypad_register: usize,
+    // The key FX0A saw go down, the wait is over once it's released
+    keypad_pressed: Option<u8>,
+
+    // XO-CHIP bitplanes that drawing, clearing and scrolling act on
+    plane: u8,
+    // XO-CHIP 1-bit audio samples, played instead of the plain buzzer once set
+    pub audio_pattern: Option<[u8; 16]>,
+    // XO-CHIP playback rate of the audio pattern, 64 is 4000 samples per second
+    pub pitch: u8,
+
+    // SUPER-CHIP persistent user flags (the HP-48 RPL registers)
+    rpl: [u8; 16],
+    exited: bool,
+
+    quirks: Quirks,
+    rng: Box<dyn RandomSource>,
+}
+
+impl Default for Chip8 {
+    fn default() -> Chip8 {
+        Chip8::new()
+    }
 }
 
 impl Chip8 {
     pub fn new() -> Chip8 {
-        let mut memory = [0u8; CHIP8_RAM];
+        Chip8::with_quirks(Quirks::default())
+    }
 
-        for i in 0..FONT_SET.len() {
-            memory[i] = FONT_SET[i];
-        }
+    pub fn with_quirks(quirks: Quirks) -> Chip8 {
+        let mut memory = vec![0u8; if quirks.extended_memory { XOCHIP_RAM } else { CHIP8_RAM }];
+
+        memory[..FONT_SET.len()].copy_from_slice(&FONT_SET);
+        memory[BIG_FONT_ADDR..(BIG_FONT_ADDR + BIG_FONT_SET.len())].copy_from_slice(&BIG_FONT_SET);
 
         Chip8 {
             i: 0,
@@ -59,21 +151,30 @@ impl Chip8 {
             sp: 0,
             v: [0; 16],
             stack: [0; 16],
-            memory: memory,
-            screen: [[0u8; CHIP8_WIDTH]; CHIP8_HEIGHT],
+            memory,
+            screen: Screen::new(),
+            screen_generation: 0,
             keypad: [false; 16],
             delay_timer: 0,
             sound_timer: 0,
             keypad_waiting: false,
             keypad_register: 0,
+            keypad_pressed: None,
+            plane: 1,
+            audio_pattern: None,
+            pitch: 64,
+            rpl: [0; 16],
+            exited: false,
+            quirks,
+            rng: Box::new(XorShift::new(default_seed())),
         }
     }
 
     pub fn load(&mut self, data: &[u8]) {
         for (i, &byte) in data.iter().enumerate() {
             let addr = START_PC + i;
-            if addr < CHIP8_RAM {
-                self.memory[START_PC + i] = byte;
+            if addr < self.memory.len() {
+                self.memory[addr] = byte;
             } else {
                 break;
             }
@@ -81,116 +182,297 @@ impl Chip8 {
 
     }
 
+    // Replaces the generator behind CXKK, for instance with a seeded one
+    pub fn set_random_source<R: RandomSource + 'static>(&mut self, source: R) {
+        self.rng = Box::new(source);
+    }
+
     pub fn set_keypad(&mut self, keyboard: [bool; 16]) {
         self.keypad = keyboard;
     }
 
-    pub fn cycle(&mut self) {
+    // Presses a single key, for frontends that get key events rather than the
+    // state of the whole keypad. Only the low nibble of `key` is used.
+    pub fn key_down(&mut self, key: u8) {
+        self.keypad[(key & 0xF) as usize] = true;
+    }
+
+    pub fn key_up(&mut self, key: u8) {
+        self.keypad[(key & 0xF) as usize] = false;
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn v(&self) -> [u8; 16] {
+        self.v
+    }
+
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    // The return addresses of the calls in progress, innermost last
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp]
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    // Changes every time the program clears, draws, scrolls or switches the
+    // resolution, and when a save state is loaded, so frontends can skip
+    // presenting a screen they already showed. Writes to `screen` from outside
+    // the VM don't change it.
+    pub fn screen_generation(&self) -> u64 {
+        self.screen_generation
+    }
+
+    fn screen_changed(&mut self) {
+        self.screen_generation = self.screen_generation.wrapping_add(1);
+    }
+
+    pub fn cycle(&mut self) -> Result<StepOutcome, Chip8Error> {
+        if self.exited {
+            return Ok(StepOutcome::Exited);
+        }
+
         if self.keypad_waiting {
-            for i in 0..self.keypad.len() {
-                if self.keypad[i] {
-                    self.keypad_waiting = false;
-                    self.v[self.keypad_register] = i as u8;
-                    break;
-                }
-            }
+            self.wait_for_key();
         } else {
-            if self.delay_timer > 0 {
-                self.delay_timer -= 1
-            }
-            if self.sound_timer > 0 {
-                self.sound_timer -= 1
+            let opcode = self.read_opcode()?;
+
+            self.eval_opcode(opcode)?;
+        }
+
+        if self.exited {
+            Ok(StepOutcome::Exited)
+        } else if self.keypad_waiting {
+            Ok(StepOutcome::WaitingForKey)
+        } else {
+            Ok(StepOutcome::Running)
+        }
+    }
+
+    // Counts both timers down, it should be called 60 times per second
+    pub fn tick_timers(&mut self) {
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1
+        }
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1
+        }
+    }
+
+    // Runs one 60 Hz frame: up to `instructions_per_frame` instructions and
+    // then a tick of the timers. It stops early if the program waits for a
+    // key or exits, since nothing can change before the next frame.
+    pub fn run_frame(&mut self, instructions_per_frame: usize) -> Result<StepOutcome, Chip8Error> {
+        let mut outcome = StepOutcome::Running;
+
+        for _ in 0..instructions_per_frame {
+            outcome = self.cycle()?;
+            if outcome != StepOutcome::Running {
+                break;
             }
+        }
+
+        self.tick_timers();
+
+        Ok(outcome)
+    }
+
+    pub fn read_opcode(&self) -> Result<u16, Chip8Error> {
+        self.opcode_at(self.pc)
+    }
+
+    fn opcode_at(&self, addr: usize) -> Result<u16, Chip8Error> {
+        if addr + 1 >= self.memory.len() {
+            return Err(Chip8Error::PcOutOfRange);
+        }
 
-            let opcode = self.read_opcode();
-
-            self.eval_opcode(opcode);
-        }
-    }
-
-    pub fn read_opcode(&self) -> u16 {
-        (self.memory[self.pc] as u16) << 8 | (self.memory[self.pc + 1] as u16)
-    }
-
-    fn eval_opcode(&mut self, opcode: u16) {
-        // These are the nibbles (groups of 4 bits)
-        let op = ((opcode & 0xF000) >> 12) as usize;
-        let x = ((opcode & 0x0F00) >> 8) as usize;
-        let y = ((opcode & 0x00F0) >> 4) as usize;
-        let n = (opcode & 0x000F) as usize;
-
-        let nnn = (opcode & 0x0FFF) as u16;
-        let kk = (opcode & 0x00FF) as u8;
-
-        let pc_change = match (op, x, y, n) {
-            (0x00, 0x00, 0x0e, 0x00) => self.op_00e0(),
-            (0x00, 0x00, 0x0e, 0x0e) => self.op_00ee(),
-            (0x01,    _,    _,    _) => self.op_1nnn(nnn),
-            (0x02,    _,    _,    _) => self.op_2nnn(nnn),
-            (0x03,    _,    _,    _) => self.op_3xkk(x, kk),
-            (0x04,    _,    _,    _) => self.op_4xkk(x, kk),
-            (0x05,    _,    _, 0x00) => self.op_5xy0(x, y),
-            (0x06,    _,    _,    _) => self.op_6xkk(x, kk),
-            (0x07,    _,    _,    _) => self.op_7xkk(x, kk),
-            (0x08,    _,    _, 0x00) => self.op_8xy0(x, y),
-            (0x08,    _,    _, 0x01) => self.op_8xy1(x, y),
-            (0x08,    _,    _, 0x02) => self.op_8xy2(x, y),
-            (0x08,    _,    _, 0x03) => self.op_8xy3(x, y),
-            (0x08,    _,    _, 0x04) => self.op_8xy4(x, y),
-            (0x08,    _,    _, 0x05) => self.op_8xy5(x, y),
-            (0x08,    _,    _, 0x06) => self.op_8xy6(x, y),
-            (0x08,    _,    _, 0x07) => self.op_8xy7(x, y),
-            (0x08,    _,    _, 0x0e) => self.op_8xye(x, y),
-            (0x09,    _,    _, 0x00) => self.op_9xy0(x, y),
-            (0x0a,    _,    _,    _) => self.op_annn(nnn),
-            (0x0b,    _,    _,    _) => self.op_bnnn(nnn),
-            (0x0c,    _,    _,    _) => self.op_cxkk(x, kk),
-            (0x0d,    _,    _,    _) => self.op_dxyn(x, y, n),
-            (0x0e,    _, 0x09, 0x0e) => self.op_ex9e(x),
-            (0x0e,    _, 0x0a, 0x01) => self.op_exa1(x),
-            (0x0f,    _, 0x00, 0x07) => self.op_fx07(x),
-            (0x0f,    _, 0x00, 0x0a) => self.op_fx0a(x),
-            (0x0f,    _, 0x01, 0x05) => self.op_fx15(x),
-            (0x0f,    _, 0x01, 0x08) => self.op_fx18(x),
-            (0x0f,    _, 0x01, 0x0e) => self.op_fx1e(x),
-            (0x0f,    _, 0x02, 0x09) => self.op_fx29(x),
-            (0x0f,    _, 0x03, 0x03) => self.op_fx33(x),
-            (0x0f,    _, 0x05, 0x05) => self.op_fx55(x),
-            (0x0f,    _, 0x06, 0x05) => self.op_fx65(x),
-            _                        => self.no_impl(opcode),
+        Ok((self.memory[addr] as u16) << 8 | (self.memory[addr + 1] as u16))
+    }
+
+    // Fails unless `len` bytes starting at `addr` are all inside the RAM
+    fn check_memory(&self, addr: usize, len: usize) -> Result<(), Chip8Error> {
+        let size = self.memory.len();
+
+        if addr + len > size {
+            Err(Chip8Error::MemoryOutOfBounds { addr: cmp::max(addr, size) })
+        } else {
+            Ok(())
+        }
+    }
+
+    fn eval_opcode(&mut self, opcode: u16) -> Result<(), Chip8Error> {
+        let instruction = match Instruction::decode(opcode) {
+            Some(instruction) => instruction,
+            None => return Err(self.no_impl(opcode)),
+        };
+
+        let pc_change = match instruction {
+            Instruction::ScrollDown(n)      => self.op_00cn(n as usize),
+            Instruction::ScrollUp(n)        => self.op_00dn(n as usize),
+            Instruction::Cls                => self.op_00e0(),
+            Instruction::Ret                => self.op_00ee()?,
+            Instruction::ScrollRight        => self.op_00fb(),
+            Instruction::ScrollLeft         => self.op_00fc(),
+            Instruction::Exit               => self.op_00fd(),
+            Instruction::Low                => self.op_00fe(),
+            Instruction::High               => self.op_00ff(),
+            Instruction::Jump(nnn)          => self.op_1nnn(nnn),
+            Instruction::Call(nnn)          => self.op_2nnn(nnn)?,
+            Instruction::SkipEqByte(x, kk)  => self.op_3xkk(x as usize, kk),
+            Instruction::SkipNeByte(x, kk)  => self.op_4xkk(x as usize, kk),
+            Instruction::SkipEqReg(x, y)    => self.op_5xy0(x as usize, y as usize),
+            Instruction::SaveRange(x, y)    => self.op_5xy2(x as usize, y as usize)?,
+            Instruction::LoadRange(x, y)    => self.op_5xy3(x as usize, y as usize)?,
+            Instruction::LoadByte(x, kk)    => self.op_6xkk(x as usize, kk),
+            Instruction::AddByte(x, kk)     => self.op_7xkk(x as usize, kk),
+            Instruction::LoadReg(x, y)      => self.op_8xy0(x as usize, y as usize),
+            Instruction::Or(x, y)           => self.op_8xy1(x as usize, y as usize),
+            Instruction::And(x, y)          => self.op_8xy2(x as usize, y as usize),
+            Instruction::Xor(x, y)          => self.op_8xy3(x as usize, y as usize),
+            Instruction::AddReg(x, y)       => self.op_8xy4(x as usize, y as usize),
+            Instruction::Sub(x, y)          => self.op_8xy5(x as usize, y as usize),
+            Instruction::Shr(x, y)          => self.op_8xy6(x as usize, y as usize),
+            Instruction::Subn(x, y)         => self.op_8xy7(x as usize, y as usize),
+            Instruction::Shl(x, y)          => self.op_8xye(x as usize, y as usize),
+            Instruction::SkipNeReg(x, y)    => self.op_9xy0(x as usize, y as usize),
+            Instruction::LoadI(nnn)         => self.op_annn(nnn),
+            Instruction::JumpV0(nnn)        => self.op_bnnn((nnn >> 8) as usize, nnn),
+            Instruction::Random(x, kk)      => self.op_cxkk(x as usize, kk),
+            Instruction::Draw(x, y, n)      => self.op_dxyn(x as usize, y as usize, n as usize)?,
+            Instruction::SkipKey(x)         => self.op_ex9e(x as usize),
+            Instruction::SkipNotKey(x)      => self.op_exa1(x as usize),
+            Instruction::LoadLongI          => self.op_f000()?,
+            Instruction::Plane(n)           => self.op_fn01(n as usize),
+            Instruction::Audio              => self.op_f002()?,
+            Instruction::LoadDelay(x)       => self.op_fx07(x as usize),
+            Instruction::WaitKey(x)         => self.op_fx0a(x as usize),
+            Instruction::SetDelay(x)        => self.op_fx15(x as usize),
+            Instruction::SetSound(x)        => self.op_fx18(x as usize),
+            Instruction::AddI(x)            => self.op_fx1e(x as usize),
+            Instruction::LoadFont(x)        => self.op_fx29(x as usize),
+            Instruction::LoadBigFont(x)     => self.op_fx30(x as usize),
+            Instruction::Bcd(x)             => self.op_fx33(x as usize)?,
+            Instruction::Pitch(x)           => self.op_fx3a(x as usize),
+            Instruction::Store(x)           => self.op_fx55(x as usize)?,
+            Instruction::Load(x)            => self.op_fx65(x as usize)?,
+            Instruction::StoreFlags(x)      => self.op_fx75(x as usize),
+            Instruction::LoadFlags(x)       => self.op_fx85(x as usize),
         };
 
         match pc_change {
             ProgramCounter::Next => self.pc += OPCODE_SIZE,
-            ProgramCounter::Skip => self.pc += 2 * OPCODE_SIZE,
+            ProgramCounter::Skip => {
+                // XO-CHIP's F000 NNNN is twice as long as any other instruction
+                if self.opcode_at(self.pc + OPCODE_SIZE) == Ok(0xF000) {
+                    self.pc += OPCODE_SIZE;
+                }
+                self.pc += 2 * OPCODE_SIZE;
+            },
             ProgramCounter::Jump(addr) => self.pc = addr as usize,
         }
+
+        Ok(())
+    }
+
+    fn op_00cn(&mut self, n: usize) -> ProgramCounter {
+        self.screen.scroll_down(n, self.plane);
+        self.screen_changed();
+
+        ProgramCounter::Next
+    }
+
+    fn op_00dn(&mut self, n: usize) -> ProgramCounter {
+        self.screen.scroll_up(n, self.plane);
+        self.screen_changed();
+
+        ProgramCounter::Next
     }
 
     fn op_00e0(&mut self) -> ProgramCounter {
-        for y in 0..CHIP8_HEIGHT {
-            for x in 0..CHIP8_WIDTH {
-                self.screen[y][x] = 0;
-            }
-        }
+        self.screen.clear_planes(self.plane);
+        self.screen_changed();
 
         ProgramCounter::Next
     }
 
-    fn op_00ee(&mut self) -> ProgramCounter {
+    fn op_00ee(&mut self) -> Result<ProgramCounter, Chip8Error> {
+        if self.sp == 0 {
+            return Err(Chip8Error::StackUnderflow);
+        }
         self.sp -= 1;
 
-        ProgramCounter::Jump(self.stack[self.sp])
+        Ok(ProgramCounter::Jump(self.stack[self.sp]))
+    }
+
+    fn op_00fb(&mut self) -> ProgramCounter {
+        self.screen.scroll_right(4, self.plane);
+        self.screen_changed();
+
+        ProgramCounter::Next
+    }
+
+    fn op_00fc(&mut self) -> ProgramCounter {
+        self.screen.scroll_left(4, self.plane);
+        self.screen_changed();
+
+        ProgramCounter::Next
+    }
+
+    fn op_00fd(&mut self) -> ProgramCounter {
+        self.exited = true;
+
+        // Stay on the exit instruction
+        ProgramCounter::Jump(self.pc as u16)
+    }
+
+    fn op_00fe(&mut self) -> ProgramCounter {
+        self.screen.set_hires(false);
+        self.screen_changed();
+
+        ProgramCounter::Next
+    }
+
+    fn op_00ff(&mut self) -> ProgramCounter {
+        self.screen.set_hires(true);
+        self.screen_changed();
+
+        ProgramCounter::Next
     }
 
     fn op_1nnn(&mut self, nnn: u16) -> ProgramCounter {
         ProgramCounter::Jump(nnn)
     }
 
-    fn op_2nnn(&mut self, nnn: u16) -> ProgramCounter {
-        self.stack[(self.sp as usize)] = (self.pc + OPCODE_SIZE) as u16;
+    fn op_2nnn(&mut self, nnn: u16) -> Result<ProgramCounter, Chip8Error> {
+        if self.sp >= self.stack.len() {
+            return Err(Chip8Error::StackOverflow);
+        }
+        self.stack[self.sp] = (self.pc + OPCODE_SIZE) as u16;
         self.sp += 1;
-        ProgramCounter::Jump(nnn)
+        Ok(ProgramCounter::Jump(nnn))
     }
 
     fn op_3xkk(&self, x: usize, kk: u8) -> ProgramCounter {
@@ -205,6 +487,32 @@ impl Chip8 {
         ProgramCounter::skip_if(self.v[x] == self.v[y])
     }
 
+    fn op_5xy2(&mut self, x: usize, y: usize) -> Result<ProgramCounter, Chip8Error> {
+        let addr = self.i as usize;
+        let count = if x <= y { y - x + 1 } else { x - y + 1 };
+        self.check_memory(addr, count)?;
+
+        for offset in 0..count {
+            let register = if x <= y { x + offset } else { x - offset };
+            self.memory[addr + offset] = self.v[register];
+        }
+
+        Ok(ProgramCounter::Next)
+    }
+
+    fn op_5xy3(&mut self, x: usize, y: usize) -> Result<ProgramCounter, Chip8Error> {
+        let addr = self.i as usize;
+        let count = if x <= y { y - x + 1 } else { x - y + 1 };
+        self.check_memory(addr, count)?;
+
+        for offset in 0..count {
+            let register = if x <= y { x + offset } else { x - offset };
+            self.v[register] = self.memory[addr + offset];
+        }
+
+        Ok(ProgramCounter::Next)
+    }
+
     fn op_6xkk(&mut self, x: usize, kk: u8) -> ProgramCounter {
         self.v[x] = kk;
         ProgramCounter::Next
@@ -222,19 +530,28 @@ impl Chip8 {
 
     fn op_8xy1(&mut self, x: usize, y: usize) -> ProgramCounter {
         self.v[x] |= self.v[y];
+        self.reset_vf_after_logic();
         ProgramCounter::Next
     }
 
     fn op_8xy2(&mut self, x: usize, y: usize) -> ProgramCounter {
         self.v[x] &= self.v[y];
+        self.reset_vf_after_logic();
         ProgramCounter::Next
     }
 
     fn op_8xy3(&mut self, x: usize, y: usize) -> ProgramCounter {
         self.v[x] ^= self.v[y];
+        self.reset_vf_after_logic();
         ProgramCounter::Next
     }
 
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
+    }
+
     fn op_8xy4(&mut self, x: usize, y: usize) -> ProgramCounter {
         self.v[x] = match self.v[x].checked_add(self.v[y]) {
             Some(n) => n,
@@ -254,9 +571,10 @@ impl Chip8 {
         ProgramCounter::Next
     }
 
-    fn op_8xy6(&mut self, x: usize, _y: usize) -> ProgramCounter {
-        self.v[0xF] = self.v[x] & 0x1;
-        self.v[x] >>= 1;
+    fn op_8xy6(&mut self, x: usize, y: usize) -> ProgramCounter {
+        let value = if self.quirks.shift_uses_vy { self.v[y] } else { self.v[x] };
+        self.v[x] = value >> 1;
+        self.v[0xF] = value & 0x1;
 
         ProgramCounter::Next
     }
@@ -268,9 +586,10 @@ impl Chip8 {
         ProgramCounter::Next
     }
 
-    fn op_8xye(&mut self, x: usize, _y: usize) -> ProgramCounter {
-        self.v[0xF] = (self.v[x] & 0b10000000) >> 7;
-        self.v[x] <<= 1;
+    fn op_8xye(&mut self, x: usize, y: usize) -> ProgramCounter {
+        let value = if self.quirks.shift_uses_vy { self.v[y] } else { self.v[x] };
+        self.v[x] = value << 1;
+        self.v[0xF] = (value & 0b10000000) >> 7;
 
         ProgramCounter::Next
     }
@@ -285,37 +604,97 @@ impl Chip8 {
         ProgramCounter::Next
     }
 
-    fn op_bnnn(&mut self, nnn: u16) -> ProgramCounter {
-        ProgramCounter::Jump(nnn + (self.v[0] as u16))
+    fn op_bnnn(&mut self, x: usize, nnn: u16) -> ProgramCounter {
+        let offset = if self.quirks.jump_uses_vx { self.v[x] } else { self.v[0] };
+
+        ProgramCounter::Jump(nnn + (offset as u16))
     }
 
     fn op_cxkk(&mut self, x: usize, kk: u8) -> ProgramCounter {
-        self.v[x] = rand::random::<u8>() & kk;
+        self.v[x] = self.rng.next_byte(&self.memory) & kk;
 
         ProgramCounter::Next
     }
 
-    fn op_dxyn(&mut self, x: usize, y: usize, n: usize) -> ProgramCounter {
+    fn op_dxyn(&mut self, x: usize, y: usize, n: usize) -> Result<ProgramCounter, Chip8Error> {
+        // DXY0 draws a 16x16 SUPER-CHIP sprite, two bytes per row
+        let (width, height) = if n == 0 { (16, 16) } else { (8, n) };
+        let row_size = width / 8;
+        let sprite_size = height * row_size;
+
+        // With both XO-CHIP planes selected, the sprite for the second plane
+        // follows the one for the first plane
+        self.check_memory(self.i as usize, sprite_size * self.plane.count_ones() as usize)?;
+
+        let screen_width = self.screen.width();
+        let screen_height = self.screen.height();
+
+        // The starting position always wraps, only the sprite itself is clipped
+        let x0 = self.v[x] as usize % screen_width;
+        let y0 = self.v[y] as usize % screen_height;
+
+        self.screen_changed();
         self.v[0xF] = 0;
-        for byte in 0..n {
-            let y = (self.v[y] as usize + byte) % CHIP8_HEIGHT;
-            for bit in 0..8 {
-                let x = (self.v[x] as usize + bit) % CHIP8_WIDTH;
-                let color = ((self.memory[(self.i as usize) + byte]) >> (7 - bit)) & 1;
-                self.v[0xF] |= color & self.screen[y][x];
-                self.screen[y][x] ^= color;
+        let mut addr = self.i as usize;
+        let selected = self.plane;
+        for plane in [0b01, 0b10].iter().filter(|&plane| selected & plane != 0) {
+            for row in 0..height {
+                let y = y0 + row;
+                if self.quirks.clip_sprites && y >= screen_height {
+                    break;
+                }
+                let y = y % screen_height;
+                for bit in 0..width {
+                    let x = x0 + bit;
+                    if self.quirks.clip_sprites && x >= screen_width {
+                        break;
+                    }
+                    let x = x % screen_width;
+                    let byte = self.memory[addr + row * row_size + bit / 8];
+                    if (byte >> (7 - bit % 8)) & 1 == 1 {
+                        if self.screen[y][x] & plane != 0 {
+                            self.v[0xF] = 1;
+                        }
+                        self.screen[y][x] ^= plane;
+                    }
+                }
             }
+            addr += sprite_size;
         }
 
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
     }
 
     fn op_ex9e(&mut self, x: usize) -> ProgramCounter {
-        ProgramCounter::skip_if(self.keypad[self.v[x] as usize])
+        ProgramCounter::skip_if(self.keypad[(self.v[x] & 0xF) as usize])
     }
 
     fn op_exa1(&mut self, x: usize) -> ProgramCounter {
-        ProgramCounter::skip_if(!self.keypad[self.v[x] as usize])
+        ProgramCounter::skip_if(!self.keypad[(self.v[x] & 0xF) as usize])
+    }
+
+    fn op_f000(&mut self) -> Result<ProgramCounter, Chip8Error> {
+        self.i = self.opcode_at(self.pc + OPCODE_SIZE)?;
+
+        // Skip over the 16 bit address as well
+        Ok(ProgramCounter::Skip)
+    }
+
+    fn op_fn01(&mut self, n: usize) -> ProgramCounter {
+        self.plane = (n & 0b11) as u8;
+
+        ProgramCounter::Next
+    }
+
+    fn op_f002(&mut self) -> Result<ProgramCounter, Chip8Error> {
+        let addr = self.i as usize;
+        self.check_memory(addr, 16)?;
+
+        let mut pattern = [0u8; 16];
+        pattern.copy_from_slice(&self.memory[addr..(addr + 16)]);
+        self.audio_pattern = Some(pattern);
+
+        Ok(ProgramCounter::Next)
     }
 
     fn op_fx07(&mut self, x: usize) -> ProgramCounter {
@@ -327,10 +706,34 @@ impl Chip8 {
     fn op_fx0a(&mut self, x: usize) -> ProgramCounter {
         self.keypad_waiting = true;
         self.keypad_register = x;
+        self.keypad_pressed = None;
 
         ProgramCounter::Next
     }
 
+    // One cycle of FX0A. Like on the COSMAC VIP, a key has to be pressed and
+    // released again, unless the quirk finishes the wait on the press. With
+    // several keys down, the lowest one counts.
+    fn wait_for_key(&mut self) {
+        let held = self.keypad.iter().position(|&down| down).map(|key| key as u8);
+
+        let key = match self.keypad_pressed {
+            _ if self.quirks.key_wait_on_press => held,
+            Some(key) if !self.keypad[key as usize] => Some(key),
+            Some(_) => None,
+            None => {
+                self.keypad_pressed = held;
+                None
+            },
+        };
+
+        if let Some(key) = key {
+            self.keypad_waiting = false;
+            self.keypad_pressed = None;
+            self.v[self.keypad_register] = key;
+        }
+    }
+
     fn op_fx15(&mut self, x: usize) -> ProgramCounter {
         self.delay_timer = self.v[x];
 
@@ -361,36 +764,79 @@ impl Chip8 {
         ProgramCounter::Next
     }
 
-    fn op_fx33(&mut self, x: usize) -> ProgramCounter {
+    fn op_fx30(&mut self, x: usize) -> ProgramCounter {
+        self.i = (BIG_FONT_ADDR + ((self.v[x] & 0xF) as usize) * 10) as u16;
+
+        ProgramCounter::Next
+    }
+
+    fn op_fx33(&mut self, x: usize) -> Result<ProgramCounter, Chip8Error> {
+        self.check_memory(self.i as usize, 3)?;
+
         self.memory[self.i as usize] = self.v[x] / 100;
         self.memory[(self.i as usize) + 1] = (self.v[x] % 100) / 10;
         self.memory[(self.i as usize) + 2] = self.v[x] % 10;
 
+        Ok(ProgramCounter::Next)
+    }
+
+    fn op_fx3a(&mut self, x: usize) -> ProgramCounter {
+        self.pitch = self.v[x];
+
         ProgramCounter::Next
     }
 
-    fn op_fx55(&mut self, x: usize) -> ProgramCounter {
-        for i in 0..(x + 1) {
-            self.memory[(self.i as usize) + i] = self.v[i];
+    fn op_fx55(&mut self, x: usize) -> Result<ProgramCounter, Chip8Error> {
+        let addr = self.i as usize;
+        self.check_memory(addr, x + 1)?;
+
+        self.memory[addr..=(addr + x)].copy_from_slice(&self.v[..=x]);
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
         }
 
-        ProgramCounter::Next
+        Ok(ProgramCounter::Next)
     }
 
-    fn op_fx65(&mut self, x: usize) -> ProgramCounter {
-        for i in 0..(x + 1) {
-            self.v[i] = self.memory[(self.i as usize) + i];
+    fn op_fx65(&mut self, x: usize) -> Result<ProgramCounter, Chip8Error> {
+        let addr = self.i as usize;
+        self.check_memory(addr, x + 1)?;
+
+        self.v[..=x].copy_from_slice(&self.memory[addr..=(addr + x)]);
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(x as u16 + 1);
         }
 
+        Ok(ProgramCounter::Next)
+    }
+
+    fn op_fx75(&mut self, x: usize) -> ProgramCounter {
+        self.rpl[..=x].copy_from_slice(&self.v[..=x]);
+
         ProgramCounter::Next
     }
 
-    fn no_impl(&self, opcode: u16) -> ProgramCounter {
-        println!("Not implemented: opcode {:x} in memory address {:x}",
-                 opcode, self.pc);
+    fn op_fx85(&mut self, x: usize) -> ProgramCounter {
+        self.v[..=x].copy_from_slice(&self.rpl[..=x]);
 
         ProgramCounter::Next
     }
+
+    fn no_impl(&self, opcode: u16) -> Chip8Error {
+        Chip8Error::UnknownOpcode { opcode, pc: self.pc }
+    }
+}
+
+// A different seed every run. Without std there's nothing to draw one from,
+// so every run is the same unless `set_random_source` gets a seeded source.
+#[cfg(feature = "std")]
+fn default_seed() -> u32 {
+    rand::random()
+}
+
+#[cfg(not(feature = "std"))]
+fn default_seed() -> u32 {
+    0
 }
 
 const FONT_SET: [u8; 80] = [
@@ -412,6 +858,26 @@ const FONT_SET: [u8; 80] = [
     0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
 
+// SUPER-CHIP 8x10 digits, with the XO-CHIP additions for A to F
+const BIG_FONT_SET: [u8; 160] = [
+    0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+    0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+    0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+    0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+    0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+    0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+    0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+    0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+    0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+    0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+    0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+    0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+    0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -420,13 +886,36 @@ mod tests {
     fn test_load_data() {
         let mut chip8 = Chip8::new();
 
-        chip8.load(&vec![1, 2, 3]);
+        chip8.load(&[1, 2, 3]);
 
         assert_eq!(chip8.memory[START_PC], 1);
         assert_eq!(chip8.memory[0x201], 2);
         assert_eq!(chip8.memory[0x202], 3);
     }
 
+    #[test]
+    fn test_op_00dn() {
+        let mut chip8 = Chip8::new();
+        chip8.screen[3][0] = 1;
+
+        chip8.eval_opcode(0x00D2).unwrap();
+
+        assert_eq!(chip8.screen[3][0], 0);
+        assert_eq!(chip8.screen[1][0], 1);
+        assert_eq!(chip8.pc, START_PC + 2);
+    }
+
+    #[test]
+    fn test_op_00e0_selected_planes() {
+        let mut chip8 = Chip8::with_quirks(Quirks::XO_CHIP);
+        chip8.screen[0][0] = 0b11;
+
+        chip8.eval_opcode(0xf201).unwrap();
+        chip8.eval_opcode(0x00E0).unwrap();
+
+        assert_eq!(chip8.screen[0][0], 0b01);
+    }
+
     #[test]
     fn test_op_00e0() {
         let mut chip8 = Chip8::new();
@@ -438,7 +927,7 @@ mod tests {
             }
         }
 
-        chip8.eval_opcode(0x00E0);
+        chip8.eval_opcode(0x00E0).unwrap();
 
         for y in 0..CHIP8_HEIGHT {
             for x in 0..CHIP8_WIDTH {
@@ -448,6 +937,18 @@ mod tests {
         assert_eq!(chip8.pc, START_PC + 2);
     }
 
+    #[test]
+    fn test_op_00cn() {
+        let mut chip8 = Chip8::new();
+        chip8.screen[0][3] = 1;
+
+        chip8.eval_opcode(0x00C2).unwrap();
+
+        assert_eq!(chip8.screen[0][3], 0);
+        assert_eq!(chip8.screen[2][3], 1);
+        assert_eq!(chip8.pc, START_PC + 2);
+    }
+
     #[test]
     fn test_op_00ee() {
         let mut chip8 = Chip8::new();
@@ -455,17 +956,68 @@ mod tests {
         chip8.sp = 5;
         chip8.stack[4] = 0x6666;
 
-        chip8.eval_opcode(0x00EE);
+        chip8.eval_opcode(0x00EE).unwrap();
 
         assert_eq!(chip8.sp, 4, "Stack Pointer");
         assert_eq!(chip8.pc, 0x6666, "Program Counter");
     }
 
+    #[test]
+    fn test_op_00fb_00fc() {
+        let mut chip8 = Chip8::new();
+        chip8.screen[0][0] = 1;
+        chip8.screen[1][CHIP8_WIDTH - 1] = 1;
+
+        chip8.eval_opcode(0x00FB).unwrap();
+
+        assert_eq!(chip8.screen[0][0], 0);
+        assert_eq!(chip8.screen[0][4], 1);
+        assert_eq!(chip8.screen[1][CHIP8_WIDTH - 1], 0);
+        assert_eq!(chip8.screen[1][CHIP8_WIDTH + 3], 0);
+
+        chip8.eval_opcode(0x00FC).unwrap();
+        chip8.eval_opcode(0x00FC).unwrap();
+
+        assert_eq!(chip8.screen[0][0], 0);
+        assert_eq!(chip8.screen[0][4], 0);
+        assert_eq!(chip8.pc, START_PC + 6);
+    }
+
+    #[test]
+    fn test_op_00fd() {
+        let mut chip8 = Chip8::new();
+        chip8.load(&[0x00, 0xFD]);
+
+        assert_eq!(chip8.cycle(), Ok(StepOutcome::Exited));
+        assert_eq!(chip8.cycle(), Ok(StepOutcome::Exited));
+        assert_eq!(chip8.pc, START_PC);
+    }
+
+    #[test]
+    fn test_op_00fe_00ff() {
+        let mut chip8 = Chip8::new();
+        chip8.screen[0][0] = 1;
+
+        chip8.eval_opcode(0x00FF).unwrap();
+
+        assert!(chip8.screen.is_hires());
+        assert_eq!(chip8.screen.width(), HIRES_WIDTH);
+        assert_eq!(chip8.screen.height(), HIRES_HEIGHT);
+        assert_eq!(chip8.screen[0][0], 0);
+
+        chip8.eval_opcode(0x00FE).unwrap();
+
+        assert!(!chip8.screen.is_hires());
+        assert_eq!(chip8.screen.width(), CHIP8_WIDTH);
+        assert_eq!(chip8.screen.height(), CHIP8_HEIGHT);
+        assert_eq!(chip8.pc, START_PC + 4);
+    }
+
     #[test]
     fn test_op_1nnn() {
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0x1F4C);
+        chip8.eval_opcode(0x1F4C).unwrap();
 
         assert_eq!(chip8.pc, 0x0F4C);
     }
@@ -474,7 +1026,7 @@ mod tests {
     fn test_op_2nnn() {
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0x2F4C);
+        chip8.eval_opcode(0x2F4C).unwrap();
 
         assert_eq!(chip8.pc, 0x0F4C, "Program Counter");
         assert_eq!(chip8.sp, 1, "Stack Pointer");
@@ -487,11 +1039,11 @@ mod tests {
         chip8.v[2] = 0x02;
         chip8.v[5] = 0x05;
 
-        chip8.eval_opcode(0x3202);
+        chip8.eval_opcode(0x3202).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 4, "Skip");
 
-        chip8.eval_opcode(0x3506);
+        chip8.eval_opcode(0x3506).unwrap();
 
         assert_eq!(chip8.pc, 0x206, "Next");
     }
@@ -500,13 +1052,13 @@ mod tests {
     fn test_op_4xkk() {
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0x4201);
+        chip8.eval_opcode(0x4201).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 4, "Skip");
 
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0x4200);
+        chip8.eval_opcode(0x4200).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 2, "Next");
     }
@@ -517,7 +1069,7 @@ mod tests {
         chip8.v[0xA] = 7;
         chip8.v[0xB] = 7;
 
-        chip8.eval_opcode(0x5AB0);
+        chip8.eval_opcode(0x5AB0).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 4, "Skip");
 
@@ -525,16 +1077,37 @@ mod tests {
         chip8.v[0xA] = 7;
         chip8.v[0xB] = 8;
 
-        chip8.eval_opcode(0x5AB0);
+        chip8.eval_opcode(0x5AB0).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 2, "Next");
     }
 
+    #[test]
+    fn test_op_5xy2_5xy3() {
+        let mut chip8 = Chip8::with_quirks(Quirks::XO_CHIP);
+        chip8.i = 0x300;
+        chip8.v[2] = 2;
+        chip8.v[3] = 3;
+        chip8.v[4] = 4;
+
+        chip8.eval_opcode(0x5242).unwrap();
+        assert_eq!(chip8.memory[0x300..0x303], [2, 3, 4]);
+
+        // A reversed range goes through the registers backwards
+        chip8.eval_opcode(0x5422).unwrap();
+        assert_eq!(chip8.memory[0x300..0x303], [4, 3, 2]);
+
+        chip8.eval_opcode(0x5673).unwrap();
+        assert_eq!(chip8.v[6..8], [4, 3]);
+        assert_eq!(chip8.i, 0x300);
+        assert_eq!(chip8.pc, START_PC + 6);
+    }
+
     #[test]
     fn test_op_6xkk() {
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0x6A89);
+        chip8.eval_opcode(0x6A89).unwrap();
 
         assert_eq!(chip8.v[0xA], 0x89);
         assert_eq!(chip8.pc, START_PC + 2);
@@ -545,7 +1118,7 @@ mod tests {
         let mut chip8 = Chip8::new();
         chip8.v[0xA] = 5;
 
-        chip8.eval_opcode(0x7A04);
+        chip8.eval_opcode(0x7A04).unwrap();
 
         assert_eq!(chip8.v[0xA], 0x09);
         assert_eq!(chip8.pc, START_PC + 2);
@@ -556,7 +1129,7 @@ mod tests {
         let mut chip8 = Chip8::new();
         chip8.v[5] = 6;
 
-        chip8.eval_opcode(0x8350);
+        chip8.eval_opcode(0x8350).unwrap();
 
         assert_eq!(chip8.v[3], 0x06);
         assert_eq!(chip8.pc, START_PC + 2);
@@ -569,7 +1142,7 @@ mod tests {
         chip8.v[1] = v2;
         chip8.v[0xF] = 0;
 
-        chip8.eval_opcode(0x8010 + op);
+        chip8.eval_opcode(0x8010 + op).unwrap();
 
         assert_eq!(chip8.v[0], result);
         assert_eq!(chip8.v[0xF], vf);
@@ -614,6 +1187,18 @@ mod tests {
         check_math(0x05, 0, 6, 0x02, 1);
     }
 
+    #[test]
+    fn test_op_8xy6_shift_uses_vy() {
+        let mut chip8 = Chip8::with_quirks(Quirks::COSMAC_VIP);
+        chip8.v[0] = 0xFF;
+        chip8.v[1] = 0x05;
+
+        chip8.eval_opcode(0x8016).unwrap();
+
+        assert_eq!(chip8.v[0], 0x02);
+        assert_eq!(chip8.v[0xF], 1);
+    }
+
     #[test]
     fn test_op_8xy7() {
         check_math(0x01, 0x0F, 7, 0x0E, 1);
@@ -626,11 +1211,35 @@ mod tests {
         check_math(0b00000111, 0, 0x0e, 0b00001110, 0);
     }
 
+    #[test]
+    fn test_op_8xye_shift_uses_vy() {
+        let mut chip8 = Chip8::with_quirks(Quirks::COSMAC_VIP);
+        chip8.v[0] = 0x01;
+        chip8.v[1] = 0b01000000;
+
+        chip8.eval_opcode(0x801e).unwrap();
+
+        assert_eq!(chip8.v[0], 0b10000000);
+        assert_eq!(chip8.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_logic_resets_vf() {
+        for op in 1..4 {
+            let mut chip8 = Chip8::with_quirks(Quirks::COSMAC_VIP);
+            chip8.v[0xF] = 1;
+
+            chip8.eval_opcode(0x8010 + op).unwrap();
+
+            assert_eq!(chip8.v[0xF], 0);
+        }
+    }
+
     #[test]
     fn test_op_9xy0() {
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0x9AB0);
+        chip8.eval_opcode(0x9AB0).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 2, "Next");
 
@@ -638,7 +1247,7 @@ mod tests {
         chip8.v[0xA] = 1;
         chip8.v[0xB] = 0;
 
-        chip8.eval_opcode(0x9AB0);
+        chip8.eval_opcode(0x9AB0).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 4, "Skip");
     }
@@ -647,7 +1256,7 @@ mod tests {
     fn test_op_annn() {
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0xa123);
+        chip8.eval_opcode(0xa123).unwrap();
 
         assert_eq!(chip8.i, 0x123);
         assert_eq!(chip8.pc, START_PC + 2);
@@ -658,28 +1267,71 @@ mod tests {
         let mut chip8 = Chip8::new();
         chip8.v[0] = 5;
 
-        chip8.eval_opcode(0xb123);
+        chip8.eval_opcode(0xb123).unwrap();
 
         assert_eq!(chip8.pc, 0x128);
     }
 
+    #[test]
+    fn test_op_bnnn_jump_uses_vx() {
+        let mut chip8 = Chip8::with_quirks(Quirks::SUPER_CHIP);
+        chip8.v[0] = 5;
+        chip8.v[1] = 7;
+
+        chip8.eval_opcode(0xb123).unwrap();
+
+        assert_eq!(chip8.pc, 0x12A);
+    }
+
     #[test]
     fn test_op_cxkk() {
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0xc000);
+        chip8.eval_opcode(0xc000).unwrap();
 
         assert_eq!(chip8.v[0], 0x0);
         assert_eq!(chip8.pc, START_PC + 2);
 
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0xc00F);
+        chip8.eval_opcode(0xc00f).unwrap();
 
         assert_eq!(chip8.v[0] & 0xF0, 0x0);
         assert_eq!(chip8.pc, START_PC + 2);
     }
 
+    #[test]
+    fn test_op_cxkk_seeded() {
+        let mut a = Chip8::new();
+        let mut b = Chip8::new();
+        a.set_random_source(XorShift::new(42));
+        b.set_random_source(XorShift::new(42));
+
+        for _ in 0..16 {
+            a.eval_opcode(0xc0ff).unwrap();
+            b.eval_opcode(0xc0ff).unwrap();
+            assert_eq!(a.v[0], b.v[0]);
+        }
+    }
+
+    struct Constant(u8);
+
+    impl RandomSource for Constant {
+        fn next_byte(&mut self, _memory: &[u8]) -> u8 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_op_cxkk_custom_source() {
+        let mut chip8 = Chip8::new();
+        chip8.set_random_source(Constant(0xAB));
+
+        chip8.eval_opcode(0xc30f).unwrap();
+
+        assert_eq!(chip8.v[3], 0x0B);
+    }
+
     #[test]
     fn test_op_dxyn() {
         let mut chip8 = Chip8::new();
@@ -693,7 +1345,7 @@ mod tests {
         chip8.screen[1][1] = 0;
         chip8.v[0] = 0;
 
-        chip8.eval_opcode(0xd002);
+        chip8.eval_opcode(0xd002).unwrap();
 
         assert_eq!(chip8.screen[0][0], 0);
         assert_eq!(chip8.screen[0][1], 1);
@@ -714,7 +1366,7 @@ mod tests {
         chip8.memory[0] = 0b11111111;
         chip8.v[0] = x as u8;
         chip8.v[1] = 0;
-        chip8.eval_opcode(0xd011);
+        chip8.eval_opcode(0xd011).unwrap();
 
         assert_eq!(chip8.screen[0][x - 1], 0);
         assert_eq!(chip8.screen[0][x], 1);
@@ -741,20 +1393,92 @@ mod tests {
         chip8.memory[1] = 0b11111111;
         chip8.v[0] = 0;
         chip8.v[1] = y as u8;
-        chip8.eval_opcode(0xd012);
+        chip8.eval_opcode(0xd012).unwrap();
 
         assert_eq!(chip8.screen[y][0], 1);
         assert_eq!(chip8.screen[0][0], 1);
         assert_eq!(chip8.v[0x0f], 0);
     }
 
+    #[test]
+    fn test_op_dxyn_clip() {
+        let mut chip8 = Chip8::with_quirks(Quirks::COSMAC_VIP);
+
+        let x = CHIP8_WIDTH - 4;
+        let y = CHIP8_HEIGHT - 1;
+
+        chip8.i = 0;
+        chip8.memory[0] = 0b11111111;
+        chip8.memory[1] = 0b11111111;
+        chip8.v[0] = x as u8;
+        chip8.v[1] = y as u8;
+        chip8.eval_opcode(0xd012).unwrap();
+
+        assert_eq!(chip8.screen[y][x + 3], 1);
+        assert_eq!(chip8.screen[y][0], 0);
+        assert_eq!(chip8.screen[0][x], 0);
+
+        // The starting position still wraps around
+        chip8.v[0] = (CHIP8_WIDTH + 1) as u8;
+        chip8.v[1] = 0;
+        chip8.eval_opcode(0xd011).unwrap();
+
+        assert_eq!(chip8.screen[0][0], 0);
+        assert_eq!(chip8.screen[0][1], 1);
+    }
+
+    #[test]
+    fn test_op_dxy0() {
+        let mut chip8 = Chip8::new();
+        chip8.eval_opcode(0x00FF).unwrap();
+
+        chip8.i = 0x300;
+        for row in 0..16 {
+            chip8.memory[0x300 + row * 2] = 0b10000000;
+            chip8.memory[0x300 + row * 2 + 1] = 0b00000001;
+        }
+        chip8.v[0] = 100;
+        chip8.v[1] = 40;
+
+        chip8.eval_opcode(0xd010).unwrap();
+
+        for y in 40..56 {
+            assert_eq!(chip8.screen[y][100], 1);
+            assert_eq!(chip8.screen[y][101], 0);
+            assert_eq!(chip8.screen[y][115], 1);
+        }
+        assert_eq!(chip8.screen[56][100], 0);
+        assert_eq!(chip8.v[0xF], 0);
+    }
+
+    #[test]
+    fn test_op_dxyn_planes() {
+        let mut chip8 = Chip8::with_quirks(Quirks::XO_CHIP);
+        chip8.i = 0x300;
+        chip8.memory[0x300] = 0b10000000;
+        chip8.memory[0x301] = 0b11000000;
+
+        chip8.eval_opcode(0xf301).unwrap();
+        chip8.eval_opcode(0xd011).unwrap();
+
+        assert_eq!(chip8.screen[0][0], 0b11);
+        assert_eq!(chip8.screen[0][1], 0b10);
+        assert_eq!(chip8.v[0xF], 0);
+
+        chip8.eval_opcode(0xf201).unwrap();
+        chip8.eval_opcode(0xd011).unwrap();
+
+        assert_eq!(chip8.screen[0][0], 0b01);
+        assert_eq!(chip8.v[0xF], 1);
+    }
+
     #[test]
     fn test_op_ex9e() {
         let mut chip8 = Chip8::new();
         chip8.keypad[0xA] = true;
         chip8.v[0x4] = 0xA;
 
-        chip8.eval_opcode(0xe49e);
+        chip8.eval_opcode(0xe49e).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 4, "keypad[v[x]] == true");
 
@@ -762,7 +1486,7 @@ mod tests {
         chip8.keypad[0xA] = true;
         chip8.v[0x4] = 0xA;
 
-        chip8.eval_opcode(0xe59e);
+        chip8.eval_opcode(0xe59e).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 2, "keypad[v[x]] == false");
     }
@@ -773,7 +1497,7 @@ mod tests {
         chip8.keypad[0xA] = true;
         chip8.v[0x4] = 0xA;
 
-        chip8.eval_opcode(0xe4a1);
+        chip8.eval_opcode(0xe4a1).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 2, "keypad[v[x]] == false");
 
@@ -781,17 +1505,81 @@ mod tests {
         chip8.keypad[0xA] = true;
         chip8.v[0x4] = 0xA;
 
-        chip8.eval_opcode(0xe5a1);
+        chip8.eval_opcode(0xe5a1).unwrap();
 
         assert_eq!(chip8.pc, START_PC + 4, "keypad[v[x]] == true");
     }
 
+    #[test]
+    fn test_op_f000() {
+        let mut chip8 = Chip8::with_quirks(Quirks::XO_CHIP);
+        chip8.load(&[0xF0, 0x00, 0xBE, 0xEF]);
+
+        chip8.cycle().unwrap();
+
+        assert_eq!(chip8.i, 0xBEEF);
+        assert_eq!(chip8.pc, START_PC + 4);
+    }
+
+    #[test]
+    fn test_skip_over_f000() {
+        let mut chip8 = Chip8::with_quirks(Quirks::XO_CHIP);
+        chip8.load(&[0x30, 0x00, 0xF0, 0x00, 0xBE, 0xEF]);
+
+        chip8.cycle().unwrap();
+
+        assert_eq!(chip8.pc, START_PC + 6);
+    }
+
+    #[test]
+    fn test_op_f002() {
+        let mut chip8 = Chip8::new();
+        chip8.i = 0x300;
+        chip8.memory[0x300] = 0xAA;
+        chip8.memory[0x30F] = 0x55;
+
+        chip8.eval_opcode(0xf002).unwrap();
+
+        let pattern = chip8.audio_pattern.unwrap();
+        assert_eq!(pattern[0], 0xAA);
+        assert_eq!(pattern[15], 0x55);
+        assert_eq!(chip8.pc, START_PC + 2);
+    }
+
+    #[test]
+    fn test_op_fx3a() {
+        let mut chip8 = Chip8::new();
+        chip8.v[5] = 112;
+
+        chip8.eval_opcode(0xf53a).unwrap();
+
+        assert_eq!(chip8.pitch, 112);
+        assert_eq!(chip8.pc, START_PC + 2);
+    }
+
+    #[test]
+    fn test_key_down_up() {
+        let mut chip8 = Chip8::new();
+        chip8.v[1] = 0xA;
+
+        chip8.key_down(0xA);
+        chip8.key_down(0x13);
+        chip8.eval_opcode(0xe19e).unwrap();
+        assert_eq!(chip8.pc, START_PC + 4);
+        assert!(chip8.keypad[0x3]);
+
+        chip8.key_up(0xA);
+        chip8.eval_opcode(0xe19e).unwrap();
+        assert_eq!(chip8.pc, START_PC + 6);
+        assert!(chip8.keypad[0x3]);
+    }
+
     #[test]
     fn test_op_fx07() {
         let mut chip8 = Chip8::new();
         chip8.delay_timer = 40;
 
-        chip8.eval_opcode(0xf407);
+        chip8.eval_opcode(0xf407).unwrap();
 
         assert_eq!(chip8.v[4], 40);
         assert_eq!(chip8.pc, START_PC + 2);
@@ -801,23 +1589,51 @@ mod tests {
     fn test_op_fx0a() {
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0xf50a);
-        assert_eq!(chip8.keypad_waiting, true);
+        chip8.eval_opcode(0xf50a).unwrap();
+        assert!(chip8.keypad_waiting);
         assert_eq!(chip8.keypad_register, 5);
         assert_eq!(chip8.pc, START_PC + 2);
 
         // Tick with no keypresses doesn't do anything
         chip8.keypad = [false; 16];
-        chip8.cycle();
-        assert_eq!(chip8.keypad_waiting, true);
+        chip8.cycle().unwrap();
+        assert!(chip8.keypad_waiting);
         assert_eq!(chip8.keypad_register, 5);
         assert_eq!(chip8.pc, START_PC + 2);
 
+        // A keypress alone doesn't finish the wait
+        chip8.keypad[0x7] = true;
+        chip8.keypad[0xC] = true;
+        chip8.cycle().unwrap();
+        assert!(chip8.keypad_waiting);
+
+        // Releasing another key doesn't either
+        chip8.keypad[0xC] = false;
+        chip8.cycle().unwrap();
+        assert!(chip8.keypad_waiting);
+
+        // Releasing the first pressed key finishes the wait and loads it
+        // into vx
+        chip8.keypad[0x7] = false;
+        chip8.cycle().unwrap();
+        assert!(!chip8.keypad_waiting);
+        assert_eq!(chip8.v[5], 0x7);
+        assert_eq!(chip8.pc, START_PC + 2);
+    }
+
+    #[test]
+    fn test_op_fx0a_on_press() {
+        let mut chip8 = Chip8::with_quirks(Quirks { key_wait_on_press: true, ..Quirks::default() });
+
+        chip8.eval_opcode(0xf50a).unwrap();
+        chip8.cycle().unwrap();
+        assert!(chip8.keypad_waiting);
+
         // Tick with a keypress finishes wait and loads
         // first pressed key into vx
         chip8.keypad = [true; 16];
-        chip8.cycle();
-        assert_eq!(chip8.keypad_waiting, false);
+        chip8.cycle().unwrap();
+        assert!(!chip8.keypad_waiting);
         assert_eq!(chip8.v[5], 0);
         assert_eq!(chip8.pc, START_PC + 2);
     }
@@ -827,7 +1643,7 @@ mod tests {
         let mut chip8 = Chip8::new();
         chip8.v[0x4] = 15;
 
-        chip8.eval_opcode(0xf415);
+        chip8.eval_opcode(0xf415).unwrap();
 
         assert_eq!(chip8.delay_timer, 15);
         assert_eq!(chip8.pc, START_PC + 2);
@@ -838,7 +1654,7 @@ mod tests {
         let mut chip8 = Chip8::new();
         chip8.v[0x4] = 15;
 
-        chip8.eval_opcode(0xf418);
+        chip8.eval_opcode(0xf418).unwrap();
 
         assert_eq!(chip8.sound_timer, 15);
         assert_eq!(chip8.pc, START_PC + 2);
@@ -850,7 +1666,7 @@ mod tests {
         chip8.i = 14;
         chip8.v[0x4] = 15;
 
-        chip8.eval_opcode(0xf41e);
+        chip8.eval_opcode(0xf41e).unwrap();
 
         assert_eq!(chip8.i, 29);
         assert_eq!(chip8.pc, START_PC + 2);
@@ -861,19 +1677,31 @@ mod tests {
         let mut chip8 = Chip8::new();
         chip8.v[0x4] = 6;
 
-        chip8.eval_opcode(0xf429);
+        chip8.eval_opcode(0xf429).unwrap();
 
         assert_eq!(chip8.i, 6 * 5);
         assert_eq!(chip8.pc, START_PC + 2);
     }
 
+    #[test]
+    fn test_op_fx30() {
+        let mut chip8 = Chip8::new();
+        chip8.v[0x4] = 6;
+
+        chip8.eval_opcode(0xf430).unwrap();
+
+        assert_eq!(chip8.i as usize, BIG_FONT_ADDR + 6 * 10);
+        assert_eq!(chip8.memory[chip8.i as usize], 0x3E);
+        assert_eq!(chip8.pc, START_PC + 2);
+    }
+
     #[test]
     fn test_op_fx33() {
         let mut chip8 = Chip8::new();
         chip8.v[5] = 123;
         chip8.i = 1000;
 
-        chip8.eval_opcode(0xf533);
+        chip8.eval_opcode(0xf533).unwrap();
 
         assert_eq!(chip8.memory[1000], 1);
         assert_eq!(chip8.memory[1001], 2);
@@ -887,7 +1715,7 @@ mod tests {
         let mut chip8 = Chip8::new();
         chip8.i = 1000;
 
-        chip8.eval_opcode(0xfF55);
+        chip8.eval_opcode(0xff55).unwrap();
 
         for i in 0..16 {
             assert_eq!(chip8.memory[1000 + i], chip8.v[i]);
@@ -904,7 +1732,7 @@ mod tests {
         }
         chip8.i = 1000;
 
-        chip8.eval_opcode(0xff65);
+        chip8.eval_opcode(0xff65).unwrap();
 
         for i in 0..16 {
             assert_eq!(chip8.v[i], chip8.memory[1000 + i]);
@@ -912,25 +1740,187 @@ mod tests {
         assert_eq!(chip8.pc, START_PC + 2);
     }
 
+    #[test]
+    fn test_op_fx75_fx85() {
+        let mut chip8 = Chip8::new();
+        for i in 0..16 {
+            chip8.v[i] = i as u8 + 1;
+        }
+
+        chip8.eval_opcode(0xf775).unwrap();
+        chip8.v = [0; 16];
+        chip8.eval_opcode(0xf385).unwrap();
+
+        assert_eq!(chip8.v[..4], [1, 2, 3, 4]);
+        assert_eq!(chip8.v[4], 0);
+        assert_eq!(chip8.rpl[7], 8);
+        assert_eq!(chip8.pc, START_PC + 4);
+    }
+
+    #[test]
+    fn test_load_store_increments_i() {
+        let mut chip8 = Chip8::with_quirks(Quirks::COSMAC_VIP);
+        chip8.i = 1000;
+
+        chip8.eval_opcode(0xf355).unwrap();
+        assert_eq!(chip8.i, 1004);
+
+        chip8.eval_opcode(0xf165).unwrap();
+        assert_eq!(chip8.i, 1006);
+    }
+
+    #[test]
+    fn test_load_store_top_of_memory() {
+        let mut chip8 = Chip8::with_quirks(Quirks::XO_CHIP);
+        chip8.v = [7; 16];
+        chip8.i = 0xfff0;
+
+        // I wraps around past the last byte of the 64 KB
+        chip8.eval_opcode(0xff55).unwrap();
+        assert_eq!(chip8.i, 0);
+        assert_eq!(&chip8.memory[0xfff0..], &[7; 16]);
+
+        chip8.v = [0; 16];
+        chip8.i = 0xfff0;
+        chip8.eval_opcode(0xff65).unwrap();
+        assert_eq!(chip8.i, 0);
+        assert_eq!(chip8.v, [7; 16]);
+    }
+
+    #[test]
+    fn test_screen_generation() {
+        let mut chip8 = Chip8::new();
+        let mut generation = chip8.screen_generation();
+
+        // Instructions that leave the screen alone
+        for &opcode in [0x6005, 0xa000, 0x7001].iter() {
+            chip8.eval_opcode(opcode).unwrap();
+            assert_eq!(chip8.screen_generation(), generation);
+        }
+
+        for &opcode in [0x00e0, 0xd015, 0x00c1, 0x00d1, 0x00fb, 0x00fc, 0x00ff, 0x00fe].iter() {
+            chip8.eval_opcode(opcode).unwrap();
+            assert_ne!(chip8.screen_generation(), generation, "{:04X}", opcode);
+            generation = chip8.screen_generation();
+        }
+
+        let state = chip8.save_state();
+        chip8.load_state(&state).unwrap();
+        assert_ne!(chip8.screen_generation(), generation);
+    }
+
     #[test]
     fn test_timers() {
         let mut chip8 = Chip8::new();
         chip8.delay_timer = 200;
         chip8.sound_timer = 100;
 
-        chip8.cycle();
+        chip8.tick_timers();
 
         assert_eq!(chip8.delay_timer, 199);
         assert_eq!(chip8.sound_timer, 99);
+        assert_eq!(chip8.pc, START_PC);
+    }
+
+    #[test]
+    fn test_cycle_leaves_timers_alone() {
+        let mut chip8 = Chip8::new();
+        chip8.load(&[0x60, 0x00]);
+        chip8.delay_timer = 200;
+
+        chip8.cycle().unwrap();
+
+        assert_eq!(chip8.delay_timer, 200);
         assert_eq!(chip8.pc, START_PC + 2);
     }
 
+    #[test]
+    fn test_run_frame() {
+        let mut chip8 = Chip8::new();
+        chip8.load(&[0x70, 0x01, 0x12, 0x00]);
+        chip8.delay_timer = 200;
+
+        assert_eq!(chip8.run_frame(10), Ok(StepOutcome::Running));
+
+        assert_eq!(chip8.v[0], 5);
+        assert_eq!(chip8.delay_timer, 199);
+    }
+
+    #[test]
+    fn test_run_frame_waiting_for_key() {
+        let mut chip8 = Chip8::new();
+        chip8.load(&[0xF0, 0x0A, 0x70, 0x01]);
+        chip8.delay_timer = 200;
+
+        assert_eq!(chip8.run_frame(10), Ok(StepOutcome::WaitingForKey));
+        assert_eq!(chip8.run_frame(10), Ok(StepOutcome::WaitingForKey));
+
+        // The timers keep running while the program waits
+        assert_eq!(chip8.delay_timer, 198);
+        assert_eq!(chip8.v[0], 0);
+    }
+
     #[test]
     fn test_no_impl() {
         let mut chip8 = Chip8::new();
 
-        chip8.eval_opcode(0xFFFF);
+        let result = chip8.eval_opcode(0xFFFF);
 
-        assert_eq!(chip8.pc, START_PC + 2);
+        assert_eq!(result, Err(Chip8Error::UnknownOpcode { opcode: 0xFFFF, pc: START_PC }));
+        assert_eq!(chip8.pc, START_PC);
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let mut chip8 = Chip8::new();
+
+        assert_eq!(chip8.eval_opcode(0x00EE), Err(Chip8Error::StackUnderflow));
+        assert_eq!(chip8.sp, 0);
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let mut chip8 = Chip8::new();
+        chip8.sp = 16;
+
+        assert_eq!(chip8.eval_opcode(0x2F4C), Err(Chip8Error::StackOverflow));
+        assert_eq!(chip8.pc, START_PC);
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        let mut chip8 = Chip8::new();
+        chip8.i = 0xFFE;
+
+        assert_eq!(chip8.eval_opcode(0xf533),
+                   Err(Chip8Error::MemoryOutOfBounds { addr: CHIP8_RAM }));
+        assert_eq!(chip8.eval_opcode(0xf355),
+                   Err(Chip8Error::MemoryOutOfBounds { addr: CHIP8_RAM }));
+        assert_eq!(chip8.eval_opcode(0xd013),
+                   Err(Chip8Error::MemoryOutOfBounds { addr: CHIP8_RAM }));
+
+        chip8.i = 0x1000;
+        assert_eq!(chip8.eval_opcode(0xf065),
+                   Err(Chip8Error::MemoryOutOfBounds { addr: 0x1000 }));
+    }
+
+    #[test]
+    fn test_extended_memory() {
+        let mut chip8 = Chip8::with_quirks(Quirks::XO_CHIP);
+        chip8.i = 0xF000;
+
+        chip8.eval_opcode(0xf055).unwrap();
+
+        chip8.i = 0xFFFF;
+        assert_eq!(chip8.eval_opcode(0xf155),
+                   Err(Chip8Error::MemoryOutOfBounds { addr: XOCHIP_RAM }));
+    }
+
+    #[test]
+    fn test_pc_out_of_range() {
+        let mut chip8 = Chip8::new();
+        chip8.pc = 0xFFF;
+
+        assert_eq!(chip8.cycle(), Err(Chip8Error::PcOutOfRange));
     }
 }
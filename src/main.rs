@@ -1,48 +1,202 @@
-use sdl2;
-use sdl2::event::Event;
-
 use std::env;
 use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::net::TcpListener;
+use std::process;
 use std::time::Duration;
-use std::thread;
 
-use chip_8::Chip8;
+use chip_8::{
+    assemble, disassemble, AudioSink, Chip8, CosmacVipRandom, DapServer, Debugger, GdbStub, InputSource,
+    Rewind, RunLoop, Tracer, VideoSink, XorShift, START_PC,
+};
 
+#[cfg(feature = "sdl")]
 mod audio;
+mod cli;
+#[cfg(feature = "sdl")]
 mod display;
+mod headless;
+#[cfg(feature = "sdl")]
 mod keyboard;
+mod keymap;
+mod terminal;
+
+use cli::{quirks_for, Command, Options, Random};
+use keymap::Keymap;
 
-const TIME : Duration = Duration::from_millis(2);
+// The timers run at 60 Hz, so the VM is driven one 60th of a second at a time
+const FRAME : Duration = Duration::from_nanos(16_666_667);
+// A minute of frames can be rewound
+const REWIND_FRAMES : usize = 60 * 60;
+const GDB_PORT : u16 = 1234;
 
 fn main() {
-    let filename = env::args().nth(1).expect("Filename not specified");
-    let data = fs::read(filename).expect("There was a problem opening the file");
+    let command = cli::parse(env::args().skip(1)).unwrap_or_else(|error| {
+        eprintln!("{}\nTry chip-8 --help", error);
+        process::exit(2);
+    });
+
+    let result = match command {
+        Command::Run(options) => run(&options),
+        Command::Tty(options) => tty(&options),
+        Command::Headless(options) => headless::run(&options),
+        Command::Disasm(rom) => disasm(&rom),
+        Command::Asm { source, output } => asm(&source, &output),
+        Command::Gdb { rom, port } => gdb(&rom, port.unwrap_or(GDB_PORT)),
+        Command::Dap => dap(),
+        Command::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        },
+    };
+
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|error| format!("{}: {}", path, error))
+}
+
+fn disasm(filename: &str) -> Result<(), String> {
+    let data = read(filename)?;
+
+    for line in disassemble(&data, START_PC) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn asm(source: &str, output: &str) -> Result<(), String> {
+    let text = fs::read_to_string(source).map_err(|error| format!("{}: {}", source, error))?;
+    let assembly = assemble(&text).map_err(|error| format!("{}: {}", source, error))?;
+
+    fs::write(output, assembly.rom).map_err(|error| format!("{}: {}", output, error))
+}
+
+fn gdb(filename: &str, port: u16) -> Result<(), String> {
+    let data = read(filename)?;
+
+    let mut chip8 = Chip8::with_quirks(quirks_for(filename));
+    chip8.load(&data);
+
+    let listener = TcpListener::bind(("127.0.0.1", port))
+        .map_err(|error| format!("There was a problem listening for gdb: {}", error))?;
+    println!("Waiting for gdb on 127.0.0.1:{}", port);
+    let (stream, _) = listener.accept().map_err(|error| format!("There was a problem accepting gdb: {}", error))?;
+    stream.set_nodelay(true).map_err(|error| error.to_string())?;
+
+    GdbStub::new(stream, Debugger::new(chip8)).run().map_err(|error| error.to_string())
+}
+
+// The editor launches the program, and talks to the adapter over stdio
+fn dap() -> Result<(), String> {
+    let stdin = io::stdin();
 
-    let     sdl_context = sdl2::init().unwrap();
-    let mut event_pump  = sdl_context.event_pump().unwrap();
+    DapServer::new(stdin.lock(), io::stdout()).run().map_err(|error| error.to_string())
+}
+
+// --trace writes every instruction to a file, and --trace-range only the
+// ones between two addresses
+fn tracer(options: &Options) -> Result<Option<Tracer<Box<dyn Write>>>, String> {
+    let path = match &options.trace {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let file = fs::File::create(path).map_err(|error| format!("{}: {}", path, error))?;
+    let output: Box<dyn Write> = Box::new(BufWriter::new(file));
+    Ok(Some(match options.trace_range.clone() {
+        Some(range) => Tracer::with_range(output, range),
+        None => Tracer::new(output),
+    }))
+}
 
-    let mut chip8   = Chip8::new();
-    let     audio   = audio::Audio::new(&sdl_context);
-    let mut display = display::Display::new(&sdl_context);
+// --keymap, or else the keymap in the configuration directory
+fn keymap(options: &Options) -> Result<Keymap, String> {
+    match &options.keymap {
+        Some(path) => Keymap::load(path, &options.rom),
+        None => Keymap::load_config(&options.rom),
+    }
+}
 
+fn load(options: &Options) -> Result<Chip8, String> {
+    let data = read(&options.rom)?;
+
+    let mut chip8 = Chip8::with_quirks(options.quirks);
     chip8.load(&data);
+    set_random(&mut chip8, options.random, options.seed);
+
+    Ok(chip8)
+}
+
+// --random and --seed
+fn set_random(chip8: &mut Chip8, random: Random, seed: Option<u32>) {
+    let seed = seed.unwrap_or_else(rand::random);
+
+    match random {
+        Random::XorShift => chip8.set_random_source(XorShift::new(seed)),
+        Random::CosmacVip => chip8.set_random_source(CosmacVipRandom::new(seed as u16)),
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn run(options: &Options) -> Result<(), String> {
+    let mut chip8 = load(options)?;
 
-    'running : loop {
-        for event in event_pump.poll_iter() {
-            if let Event::Quit {..} = event {
-                break 'running;
-            };
-        };
+    let     sdl_context = sdl2::init()?;
+    let mut keyboard    = keyboard::Keyboard::new(sdl_context.event_pump()?, &keymap(options)?)?;
+    let mut display     = display::Display::new(&sdl_context, options.scale, options.palette, options.vsync)?;
 
-        let keyboard = keyboard::poll(&event_pump);
-        chip8.set_keypad(keyboard);
+    if options.mute {
+        play(&mut chip8, &mut display, &mut headless::Discard, &mut keyboard, options)
+    } else {
+        let mut audio = audio::Audio::new(&sdl_context, options.tone)?;
+        play(&mut chip8, &mut display, &mut audio, &mut keyboard, options)
+    }
+}
 
-        chip8.cycle();
+#[cfg(not(feature = "sdl"))]
+fn run(_options: &Options) -> Result<(), String> {
+    Err("This build has no SDL window, try the tty or headless commands".to_string())
+}
+
+// Plays in the terminal, Escape quits
+fn tty(options: &Options) -> Result<(), String> {
+    let mut chip8 = load(options)?;
+    let keymap    = keymap(options)?;
+
+    let terminal = terminal::Terminal::new()
+        .map_err(|error| format!("There was a problem setting up the terminal: {}", error))?;
+    let mut video = terminal.video(options.palette);
+    let mut input = terminal.input(&keymap);
+
+    // The terminal is dropped, leaving the alternate screen, before main
+    // prints an error
+    if options.mute {
+        play(&mut chip8, &mut video, &mut headless::Discard, &mut input, options)
+    } else {
+        play(&mut chip8, &mut video, &mut terminal.audio(), &mut input, options)
+    }
+}
 
-        display.draw(&chip8.screen);
+// Plays a ROM in real time, with rewinding
+fn play<V, A, I>(chip8: &mut Chip8, video: &mut V, audio: &mut A, input: &mut I, options: &Options)
+    -> Result<(), String>
+    where V: VideoSink, A: AudioSink, I: InputSource
+{
+    let mut run_loop = RunLoop::new(options.instructions_per_frame());
+    run_loop.frame = Some(FRAME);
+    run_loop.rewind = Some(Rewind::new(REWIND_FRAMES));
+    run_loop.tracer = tracer(options)?;
+    run_loop.paused = options.paused;
 
-        audio.beep(&chip8.sound_timer);
+    let result = run_loop.run(chip8, video, audio, input).map_err(|error| error.to_string());
 
-        thread::sleep(TIME);
+    if let Some(Err(error)) = run_loop.tracer.take().map(Tracer::finish) {
+        return Err(format!("There was a problem writing the trace: {}", error));
     }
+    result
 }
@@ -0,0 +1,96 @@
+// Several CHIP-8 instructions were implemented differently by the original
+// COSMAC VIP interpreter and by the later CHIP-48, SUPER-CHIP and XO-CHIP
+// ones, and ROMs tend to rely on the behaviour of the interpreter they were
+// written for. Every flag here picks one reading of a disputed instruction.
+//
+// `Quirks::default()` is the behaviour this VM has always had, except for FX0A
+// which used to finish on a key press.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Quirks {
+    // 8XY6 and 8XYE shift Vy into Vx instead of shifting Vx in place
+    pub shift_uses_vy: bool,
+    // FX55 and FX65 leave I pointing past the last register they touched
+    pub load_store_increments_i: bool,
+    // BNNN is read as BXNN and jumps to XNN + Vx instead of NNN + V0
+    pub jump_uses_vx: bool,
+    // DXYN clips sprites at the screen edges instead of wrapping them around
+    pub clip_sprites: bool,
+    // 8XY1, 8XY2 and 8XY3 set VF to 0
+    pub logic_resets_vf: bool,
+    // The RAM is the 64 KiB of XO-CHIP instead of 4 KiB
+    pub extended_memory: bool,
+    // FX0A finishes as soon as a key is down, instead of once it's released
+    pub key_wait_on_press: bool,
+}
+
+impl Quirks {
+    // The original interpreter of the RCA COSMAC VIP (1977)
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        jump_uses_vx: false,
+        clip_sprites: true,
+        logic_resets_vf: true,
+        extended_memory: false,
+        key_wait_on_press: false,
+    };
+
+    // CHIP-48 for the HP-48 calculators (1990)
+    pub const CHIP_48: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        jump_uses_vx: true,
+        clip_sprites: true,
+        logic_resets_vf: false,
+        extended_memory: false,
+        key_wait_on_press: false,
+    };
+
+    // SUPER-CHIP 1.1 (1991)
+    pub const SUPER_CHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        jump_uses_vx: true,
+        clip_sprites: true,
+        logic_resets_vf: false,
+        extended_memory: false,
+        key_wait_on_press: false,
+    };
+
+    // XO-CHIP, as implemented by Octo (2014)
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        jump_uses_vx: false,
+        clip_sprites: false,
+        logic_resets_vf: false,
+        extended_memory: true,
+        key_wait_on_press: false,
+    };
+
+    // Looks up a set of quirks by the name of its interpreter, "chip-8" being
+    // the default
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name.to_lowercase().replace('-', "").as_str() {
+            "chip8" => Some(Quirks::default()),
+            "vip" | "cosmacvip" => Some(Quirks::COSMAC_VIP),
+            "chip48" => Some(Quirks::CHIP_48),
+            "schip" | "superchip" => Some(Quirks::SUPER_CHIP),
+            "xochip" => Some(Quirks::XO_CHIP),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(Quirks::from_name("chip-8"), Some(Quirks::default()));
+        assert_eq!(Quirks::from_name("SCHIP"), Some(Quirks::SUPER_CHIP));
+        assert_eq!(Quirks::from_name("xo-chip"), Some(Quirks::XO_CHIP));
+        assert_eq!(Quirks::from_name("chip-9"), None);
+    }
+}
@@ -0,0 +1,142 @@
+// Where CXKK gets its random bytes from. Sources get the RAM of the VM, which
+// the COSMAC VIP's routine draws from.
+pub trait RandomSource {
+    fn next_byte(&mut self, memory: &[u8]) -> u8;
+
+    // The state of the generator, saved with the rest of the VM so that a
+    // restored VM draws the same numbers again. Sources that can't be
+    // restored keep the defaults.
+    fn state(&self) -> u32 {
+        0
+    }
+
+    fn restore(&mut self, _state: u32) {}
+}
+
+// The default source, a 32 bit xorshift generator. Two VMs seeded with the
+// same value will draw the same numbers, which makes runs reproducible.
+#[derive(Debug, Clone)]
+pub struct XorShift {
+    state: u32,
+}
+
+impl XorShift {
+    pub fn new(seed: u32) -> XorShift {
+        // Xorshift gets stuck on zero
+        XorShift { state: if seed == 0 { 0x9E37_79B9 } else { seed } }
+    }
+}
+
+impl RandomSource for XorShift {
+    fn next_byte(&mut self, _memory: &[u8]) -> u8 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+
+        (self.state >> 24) as u8
+    }
+
+    fn state(&self) -> u32 {
+        self.state
+    }
+
+    fn restore(&mut self, state: u32) {
+        *self = XorShift::new(state);
+    }
+}
+
+// The routine of the original COSMAC VIP interpreter, which didn't have a real
+// generator. It kept a 16 bit register, R9, incremented it on every CXKK, and
+// added the byte its low half pointed at in a page of RAM to its high half,
+// which became the random number. Its numbers are therefore far from uniform,
+// and some ROMs were tuned to that.
+//
+// The VIP walked over the page of its own interpreter code. Here the part of
+// RAM below 0x200 holds the fonts instead, so the numbers follow the VIP's
+// routine but come out of different bytes. `with_page` walks another page,
+// one a dump of the VIP interpreter was copied into for instance.
+#[derive(Debug, Clone)]
+pub struct CosmacVipRandom {
+    r9: u16,
+    // The address of the page
+    page: usize,
+}
+
+impl CosmacVipRandom {
+    pub fn new(seed: u16) -> CosmacVipRandom {
+        CosmacVipRandom::with_page(seed, 0)
+    }
+
+    pub fn with_page(seed: u16, page: usize) -> CosmacVipRandom {
+        CosmacVipRandom { r9: seed, page }
+    }
+}
+
+impl RandomSource for CosmacVipRandom {
+    fn next_byte(&mut self, memory: &[u8]) -> u8 {
+        self.r9 = self.r9.wrapping_add(1);
+        let byte = memory.get(self.page + (self.r9 & 0xFF) as usize).copied().unwrap_or(0);
+        let high = ((self.r9 >> 8) as u8).wrapping_add(byte);
+        self.r9 = (high as u16) << 8 | (self.r9 & 0xFF);
+
+        high
+    }
+
+    fn state(&self) -> u32 {
+        self.r9 as u32
+    }
+
+    fn restore(&mut self, state: u32) {
+        self.r9 = state as u16;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xorshift_is_reproducible() {
+        let mut a = XorShift::new(1234);
+        let mut b = XorShift::new(1234);
+
+        for _ in 0..100 {
+            assert_eq!(a.next_byte(&[]), b.next_byte(&[]));
+        }
+    }
+
+    #[test]
+    fn test_xorshift_zero_seed() {
+        let mut rng = XorShift::new(0);
+
+        assert!((0..100).any(|_| rng.next_byte(&[]) != 0));
+    }
+
+    #[test]
+    fn test_xorshift_restore() {
+        let mut a = XorShift::new(99);
+        a.next_byte(&[]);
+        let mut b = XorShift::new(1);
+        b.restore(a.state());
+
+        assert_eq!(a.next_byte(&[]), b.next_byte(&[]));
+    }
+
+    #[test]
+    fn test_cosmac_vip_random() {
+        let mut memory = [0u8; 0x200];
+        memory[0x101] = 3;
+        memory[0x102] = 5;
+        memory[0x100] = 7;
+        let mut rng = CosmacVipRandom::with_page(0x1000, 0x100);
+
+        assert_eq!(rng.next_byte(&memory), 0x13);
+        assert_eq!(rng.next_byte(&memory), 0x18);
+        assert_eq!(rng.next_byte(&memory), 0x18);
+
+        // The low half carries into the high half as it wraps around
+        let mut rng = CosmacVipRandom::with_page(0x10FF, 0x100);
+        assert_eq!(rng.next_byte(&memory), 0x11 + 7);
+        assert_eq!(rng.state(), 0x1800);
+    }
+}
@@ -0,0 +1,205 @@
+// A rewind buffer: a ring of per-frame snapshots that can be stepped back
+// through one frame at a time.
+//
+// Only the most recent frame is kept as a full save state. Every older frame
+// is stored as a delta holding just the bytes that differ from the frame after
+// it, which for most games is a handful of registers and a few pixels.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+use crate::Chip8;
+
+// Runs of changed bytes closer than this are merged, which saves the cost of a
+// run header for every lone pixel
+const MERGE_GAP: usize = 8;
+
+pub struct Rewind {
+    capacity: usize,
+    // Full save state of the last frame pushed
+    latest: Vec<u8>,
+    // Oldest first, each delta turns the frame after it into its own frame
+    deltas: VecDeque<Delta>,
+}
+
+impl Rewind {
+    // `capacity` is the number of frames it's possible to step back
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity,
+            latest: Vec::new(),
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    // Records the state of the VM at the end of a frame
+    pub fn push(&mut self, chip8: &Chip8) {
+        let state = chip8.save_state();
+
+        if self.latest.len() != state.len() {
+            self.deltas.clear();
+        } else if self.capacity > 0 {
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            self.deltas.push_back(Delta::between(&state, &self.latest));
+        }
+
+        self.latest = state;
+    }
+
+    // Takes the VM back to the frame before the last one pushed. Returns false
+    // once there is no older frame left.
+    pub fn rewind(&mut self, chip8: &mut Chip8) -> bool {
+        match self.deltas.pop_back() {
+            Some(delta) => {
+                delta.apply(&mut self.latest);
+                chip8.load_state(&self.latest).is_ok()
+            },
+            None => false,
+        }
+    }
+
+    // Number of frames it's possible to step back
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest.clear();
+        self.deltas.clear();
+    }
+
+    // Bytes used by the stored frames
+    pub fn memory_usage(&self) -> usize {
+        self.latest.len() + self.deltas.iter().map(|delta| delta.data.len()).sum::<usize>()
+    }
+}
+
+// A list of runs, each one a 4 byte offset, a 2 byte length and the bytes to
+// write there
+struct Delta {
+    data: Vec<u8>,
+}
+
+impl Delta {
+    // The runs that turn `from` into `to`, which must be the same length
+    fn between(from: &[u8], to: &[u8]) -> Delta {
+        let mut data = Vec::new();
+        let mut offset = 0;
+
+        while offset < from.len() {
+            if from[offset] == to[offset] {
+                offset += 1;
+                continue;
+            }
+
+            // Extend the run until MERGE_GAP bytes in a row are unchanged
+            let start = offset;
+            let mut end = offset + 1;
+            let mut unchanged = 0;
+            while end < from.len() && unchanged < MERGE_GAP && end - start < u16::MAX as usize {
+                if from[end] == to[end] {
+                    unchanged += 1;
+                } else {
+                    unchanged = 0;
+                }
+                end += 1;
+            }
+            let end = end - unchanged;
+
+            data.extend_from_slice(&(start as u32).to_le_bytes());
+            data.extend_from_slice(&((end - start) as u16).to_le_bytes());
+            data.extend_from_slice(&to[start..end]);
+            offset = end;
+        }
+
+        Delta { data }
+    }
+
+    fn apply(&self, state: &mut [u8]) {
+        let mut data = &self.data[..];
+
+        while !data.is_empty() {
+            let start = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            let len = u16::from_le_bytes([data[4], data[5]]) as usize;
+            state[start..(start + len)].copy_from_slice(&data[6..(6 + len)]);
+            data = &data[(6 + len)..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts up in V0 and draws the matching digit, forever
+    const ROM: [u8; 12] = [
+        0x00, 0xE0, // CLS
+        0xF0, 0x29, // LD F, V0
+        0xD1, 0x15, // DRW V1, V1, 5
+        0x70, 0x01, // ADD V0, 1
+        0x60, 0x0F, // LD V0, 0x0F
+        0x12, 0x00, // JP 0x200
+    ];
+
+    #[test]
+    fn test_rewind() {
+        let mut chip8 = Chip8::new();
+        chip8.load(&ROM);
+        let mut rewind = Rewind::new(100);
+        let mut states = Vec::new();
+
+        for _ in 0..10 {
+            chip8.run_frame(3).unwrap();
+            rewind.push(&chip8);
+            states.push(chip8.save_state());
+        }
+        assert_eq!(rewind.len(), 9);
+
+        for frame in (0..9).rev() {
+            assert!(rewind.rewind(&mut chip8));
+            assert_eq!(chip8.save_state(), states[frame]);
+        }
+        assert!(!rewind.rewind(&mut chip8));
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut chip8 = Chip8::new();
+        chip8.load(&ROM);
+        let mut rewind = Rewind::new(4);
+
+        for _ in 0..10 {
+            chip8.run_frame(3).unwrap();
+            rewind.push(&chip8);
+        }
+        assert_eq!(rewind.len(), 4);
+
+        let mut steps = 0;
+        while rewind.rewind(&mut chip8) {
+            steps += 1;
+        }
+        assert_eq!(steps, 4);
+    }
+
+    #[test]
+    fn test_deltas_are_small() {
+        let mut chip8 = Chip8::new();
+        chip8.load(&ROM);
+        let mut rewind = Rewind::new(100);
+
+        for _ in 0..60 {
+            chip8.run_frame(6).unwrap();
+            rewind.push(&chip8);
+        }
+
+        let full_state = chip8.save_state().len();
+        assert!(rewind.memory_usage() < full_state * 2);
+    }
+}
@@ -0,0 +1,119 @@
+use core::ops::{Index, IndexMut};
+
+use crate::{CHIP8_HEIGHT, CHIP8_WIDTH, HIRES_HEIGHT, HIRES_WIDTH};
+
+// The framebuffer. It's always big enough for the SUPER-CHIP high resolution
+// mode, and in low resolution mode only the top left 64x32 pixels are used.
+//
+// Every pixel is a bitmask of the XO-CHIP bitplanes it is lit on: bit 0 for
+// the first plane and bit 1 for the second one, so a pixel is one of four
+// colours. Plain CHIP-8 and SUPER-CHIP programs only ever draw on the first
+// plane, which leaves every pixel either 0 or 1.
+#[derive(Clone)]
+pub struct Screen {
+    pub(crate) pixels: [[u8; HIRES_WIDTH]; HIRES_HEIGHT],
+    pub(crate) hires: bool,
+}
+
+impl Default for Screen {
+    fn default() -> Screen {
+        Screen::new()
+    }
+}
+
+impl Screen {
+    pub fn new() -> Screen {
+        Screen {
+            pixels: [[0u8; HIRES_WIDTH]; HIRES_HEIGHT],
+            hires: false,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        if self.hires { HIRES_WIDTH } else { CHIP8_WIDTH }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.hires { HIRES_HEIGHT } else { CHIP8_HEIGHT }
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    // Switching resolution clears the screen
+    pub fn set_hires(&mut self, hires: bool) {
+        self.hires = hires;
+        self.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.pixels = [[0u8; HIRES_WIDTH]; HIRES_HEIGHT];
+    }
+
+    pub fn clear_planes(&mut self, planes: u8) {
+        for row in self.pixels.iter_mut() {
+            for pixel in row.iter_mut() {
+                *pixel &= !planes;
+            }
+        }
+    }
+
+    // The visible rows, each one as wide as the current resolution
+    pub fn rows(&self) -> impl Iterator<Item = &[u8]> {
+        let width = self.width();
+
+        self.pixels[..self.height()].iter().map(move |row| &row[..width])
+    }
+
+    pub fn scroll_down(&mut self, n: usize, planes: u8) {
+        self.scroll(0, n as isize, planes);
+    }
+
+    pub fn scroll_up(&mut self, n: usize, planes: u8) {
+        self.scroll(0, -(n as isize), planes);
+    }
+
+    pub fn scroll_right(&mut self, n: usize, planes: u8) {
+        self.scroll(n as isize, 0, planes);
+    }
+
+    pub fn scroll_left(&mut self, n: usize, planes: u8) {
+        self.scroll(-(n as isize), 0, planes);
+    }
+
+    // Moves the selected planes by (dx, dy), filling the gap with unlit pixels
+    fn scroll(&mut self, dx: isize, dy: isize, planes: u8) {
+        let width = self.width() as isize;
+        let height = self.height() as isize;
+        let old = self.pixels;
+
+        for y in 0..height {
+            for x in 0..width {
+                let (from_x, from_y) = (x - dx, y - dy);
+                let moved = if from_x >= 0 && from_x < width && from_y >= 0 && from_y < height {
+                    old[from_y as usize][from_x as usize]
+                } else {
+                    0
+                };
+
+                let pixel = &mut self.pixels[y as usize][x as usize];
+                *pixel = (*pixel & !planes) | (moved & planes);
+            }
+        }
+    }
+}
+
+impl Index<usize> for Screen {
+    type Output = [u8; HIRES_WIDTH];
+
+    fn index(&self, y: usize) -> &[u8; HIRES_WIDTH] {
+        &self.pixels[y]
+    }
+}
+
+impl IndexMut<usize> for Screen {
+    fn index_mut(&mut self, y: usize) -> &mut [u8; HIRES_WIDTH] {
+        &mut self.pixels[y]
+    }
+}
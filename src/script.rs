@@ -0,0 +1,112 @@
+// Scripted key presses, to play a ROM without a keyboard. A script has one
+// event per line, the frame it happens on, then down or up, then the key:
+//
+//     # Start the game, then hold 4 for a second
+//     10 down 5
+//     12 up 5
+//     60 down 4
+//     120 up 4
+//
+// Frames count from 0, keys are hexadecimal, and '#' starts a comment.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::ScriptError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub frame: u64,
+    pub key: u8,
+    pub down: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyScript {
+    // In frame order, events of the same frame in script order
+    events: Vec<KeyEvent>,
+}
+
+impl KeyScript {
+    pub fn parse(text: &str) -> Result<KeyScript, ScriptError> {
+        let mut events = Vec::new();
+
+        for (index, line) in text.lines().enumerate() {
+            let error = |message: String| ScriptError { line: index + 1, message };
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(error(format!("expected '<frame> <down|up> <key>', found '{}'", line)));
+            }
+
+            let frame = fields[0].parse().map_err(|_| error(format!("invalid frame {}", fields[0])))?;
+            let down = match fields[1] {
+                "down" => true,
+                "up" => false,
+                other => return Err(error(format!("expected down or up, found {}", other))),
+            };
+            let key = match u8::from_str_radix(fields[2], 16) {
+                Ok(key) if key < 16 => key,
+                _ => return Err(error(format!("invalid key {}", fields[2]))),
+            };
+
+            events.push(KeyEvent { frame, key, down });
+        }
+        events.sort_by_key(|event| event.frame);
+
+        Ok(KeyScript { events })
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    // Presses and releases the keys of `frame`
+    pub fn apply(&self, frame: u64, keypad: &mut [bool; 16]) {
+        for event in self.events.iter().filter(|event| event.frame == frame) {
+            keypad[event.key as usize] = event.down;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_apply() {
+        let script = KeyScript::parse("
+            # comment
+            12 up 5
+            10 down 5   # start
+            10 down A
+        ").unwrap();
+
+        assert_eq!(script.events()[0], KeyEvent { frame: 10, key: 5, down: true });
+        assert_eq!(script.events().len(), 3);
+
+        let mut keypad = [false; 16];
+        script.apply(10, &mut keypad);
+        assert!(keypad[5] && keypad[0xA]);
+        script.apply(11, &mut keypad);
+        assert!(keypad[5]);
+        script.apply(12, &mut keypad);
+        assert!(!keypad[5] && keypad[0xA]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text: &str| KeyScript::parse(text).unwrap_err();
+
+        assert_eq!(error("1 down 1\n2 sideways 1"),
+                   ScriptError { line: 2, message: "expected down or up, found sideways".to_string() });
+        assert_eq!(error("1 down 10").message, "invalid key 10");
+        assert_eq!(error("-1 down 1").message, "invalid frame -1");
+        assert_eq!(error("1 down").message, "expected '<frame> <down|up> <key>', found '1 down'");
+    }
+}
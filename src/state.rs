@@ -0,0 +1,388 @@
+// Save states: a snapshot of the whole machine as a flat binary blob.
+//
+// All numbers are little endian. A snapshot is laid out as follows:
+//
+//   bytes  field
+//   4      magic, the ASCII characters "C8SS"
+//   2      format version, currently 3
+//   16     V0 to VF
+//   2      I
+//   4      program counter
+//   1      stack pointer
+//   32     the 16 stack levels, 2 bytes each
+//   1      delay timer
+//   1      sound timer
+//   2      keypad, bit N set when key N is held down
+//   1      1 while FX0A waits for a key, 0 otherwise
+//   1      the register FX0A stores the key in
+//   1      selected XO-CHIP bitplanes
+//   1      XO-CHIP pitch
+//   1      1 if an XO-CHIP audio pattern was loaded, 0 otherwise
+//   16     the audio pattern, zeroes if none was loaded
+//   16     SUPER-CHIP RPL flags
+//   1      1 once the program ran 00FD, 0 otherwise
+//   1      1 in high resolution mode, 0 otherwise
+//   8192   the 128x64 framebuffer, row by row, one byte per pixel holding the
+//          bitplanes it is lit on
+//   4      size of the RAM
+//   ...    the RAM
+//   1      the key FX0A saw pressed and waits to be released, 0xFF if none,
+//          since version 2
+//   4      the state of the random number generator, since version 3
+//
+// Fields only ever get appended in new versions, so older snapshots can still
+// be read. The quirks and which random number generator CXKK uses are
+// configuration rather than state, and are not saved.
+
+use alloc::vec::Vec;
+
+use crate::{Chip8, StateError, HIRES_HEIGHT, HIRES_WIDTH};
+
+const MAGIC: &[u8; 4] = b"C8SS";
+const VERSION: u16 = 3;
+
+impl Chip8 {
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HIRES_WIDTH * HIRES_HEIGHT + self.memory.len() + 128);
+
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&self.v);
+        out.extend_from_slice(&self.i.to_le_bytes());
+        out.extend_from_slice(&(self.pc as u32).to_le_bytes());
+        out.push(self.sp as u8);
+        for level in self.stack.iter() {
+            out.extend_from_slice(&level.to_le_bytes());
+        }
+        out.push(self.delay_timer);
+        out.push(self.sound_timer);
+        let keypad = self.keypad.iter().enumerate()
+            .fold(0u16, |keys, (key, &down)| keys | ((down as u16) << key));
+        out.extend_from_slice(&keypad.to_le_bytes());
+        out.push(self.keypad_waiting as u8);
+        out.push(self.keypad_register as u8);
+        out.push(self.plane);
+        out.push(self.pitch);
+        out.push(self.audio_pattern.is_some() as u8);
+        out.extend_from_slice(&self.audio_pattern.unwrap_or([0; 16]));
+        out.extend_from_slice(&self.rpl);
+        out.push(self.exited as u8);
+        out.push(self.screen.hires as u8);
+        for row in self.screen.pixels.iter() {
+            out.extend_from_slice(row);
+        }
+        out.extend_from_slice(&(self.memory.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.memory);
+        out.push(self.keypad_pressed.unwrap_or(0xFF));
+        out.extend_from_slice(&self.rng.state().to_le_bytes());
+
+        out
+    }
+
+    // Restores a snapshot taken with `save_state`. On error the VM is left
+    // untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = Reader { data };
+
+        if reader.bytes(MAGIC.len())? != MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = reader.u16()?;
+        if version == 0 || version > VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let mut v = [0u8; 16];
+        v.copy_from_slice(reader.bytes(16)?);
+        let i = reader.u16()?;
+        let pc = reader.u32()? as usize;
+        let sp = reader.u8()? as usize;
+        let mut stack = [0u16; 16];
+        for level in stack.iter_mut() {
+            *level = reader.u16()?;
+        }
+        let delay_timer = reader.u8()?;
+        let sound_timer = reader.u8()?;
+        let keys = reader.u16()?;
+        let mut keypad = [false; 16];
+        for (key, down) in keypad.iter_mut().enumerate() {
+            *down = keys & (1 << key) != 0;
+        }
+        let keypad_waiting = reader.bool()?;
+        let keypad_register = reader.u8()? as usize;
+        let plane = reader.u8()?;
+        let pitch = reader.u8()?;
+        let has_pattern = reader.bool()?;
+        let mut pattern = [0u8; 16];
+        pattern.copy_from_slice(reader.bytes(16)?);
+        let mut rpl = [0u8; 16];
+        rpl.copy_from_slice(reader.bytes(16)?);
+        let exited = reader.bool()?;
+        let hires = reader.bool()?;
+        let pixels = reader.bytes(HIRES_WIDTH * HIRES_HEIGHT)?;
+        let memory_size = reader.u32()? as usize;
+        if memory_size != self.memory.len() {
+            return Err(StateError::MemorySizeMismatch {
+                expected: self.memory.len(),
+                found: memory_size,
+            });
+        }
+        let memory = reader.bytes(memory_size)?;
+        let keypad_pressed = match version {
+            1 => None,
+            _ => match reader.u8()? {
+                0xFF => None,
+                key if key <= 0xF => Some(key),
+                _ => return Err(StateError::Invalid),
+            },
+        };
+        let rng = match version {
+            1 | 2 => None,
+            _ => Some(reader.u32()?),
+        };
+        if !reader.data.is_empty() {
+            return Err(StateError::TrailingData);
+        }
+
+        // A pixel holds the bitplanes it's lit on
+        let pixels_valid = pixels.iter().all(|&pixel| pixel <= 0b11);
+        if sp > stack.len() || keypad_register > 0xF || plane > 0b11 || pc >= memory_size || !pixels_valid {
+            return Err(StateError::Invalid);
+        }
+
+        self.v = v;
+        self.i = i;
+        self.pc = pc;
+        self.sp = sp;
+        self.stack = stack;
+        self.delay_timer = delay_timer;
+        self.sound_timer = sound_timer;
+        self.keypad = keypad;
+        self.keypad_waiting = keypad_waiting;
+        self.keypad_register = keypad_register;
+        self.keypad_pressed = keypad_pressed;
+        self.plane = plane;
+        self.pitch = pitch;
+        self.audio_pattern = if has_pattern { Some(pattern) } else { None };
+        self.rpl = rpl;
+        self.exited = exited;
+        self.screen.hires = hires;
+        for (row, data) in self.screen.pixels.iter_mut().zip(pixels.chunks(HIRES_WIDTH)) {
+            row.copy_from_slice(data);
+        }
+        self.memory.copy_from_slice(memory);
+        if let Some(state) = rng {
+            self.rng.restore(state);
+        }
+        self.screen_changed();
+
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.bytes(2)?;
+
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.bytes(4)?;
+
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Chip8, CosmacVipRandom, Quirks, StateError, StepOutcome, XorShift};
+
+    // A ROM that calls a subroutine, draws the 0 digit and waits for a key
+    const ROM: [u8; 12] = [
+        0x22, 0x04, // CALL 0x204
+        0xF5, 0x0A, // LD V5, K
+        0x6A, 0x0C, // LD VA, 0x0C
+        0xF0, 0x29, // LD F, V0
+        0xD0, 0x15, // DRW V0, V1, 5
+        0x00, 0xEE, // RET
+    ];
+
+    fn running_vm() -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load(&ROM);
+        chip8.delay_timer = 30;
+        chip8.sound_timer = 20;
+        chip8.keypad[3] = true;
+        chip8.audio_pattern = Some([0xF0; 16]);
+        chip8.rpl[2] = 9;
+        while chip8.cycle().unwrap() == StepOutcome::Running {}
+        // FX0A sees the key that's held down, and waits for its release
+        chip8.cycle().unwrap();
+
+        chip8
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let chip8 = running_vm();
+        let state = chip8.save_state();
+
+        let mut restored = Chip8::new();
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.v, chip8.v);
+        assert_eq!(restored.i, chip8.i);
+        assert_eq!(restored.pc, chip8.pc);
+        assert_eq!(restored.sp, chip8.sp);
+        assert_eq!(restored.stack, chip8.stack);
+        assert_eq!(restored.memory, chip8.memory);
+        assert_eq!(restored.screen.pixels[..], chip8.screen.pixels[..]);
+        assert_eq!(restored.keypad, chip8.keypad);
+        assert_eq!(restored.delay_timer, 30);
+        assert_eq!(restored.sound_timer, 20);
+        assert!(restored.keypad_waiting);
+        assert_eq!(restored.keypad_register, 5);
+        assert_eq!(restored.keypad_pressed, Some(3));
+        assert_eq!(restored.audio_pattern, Some([0xF0; 16]));
+        assert_eq!(restored.rpl[2], 9);
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn test_random_numbers_replay() {
+        for vip in [false, true] {
+            let mut chip8 = Chip8::new();
+            if vip {
+                chip8.set_random_source(CosmacVipRandom::new(0x1234));
+            } else {
+                chip8.set_random_source(XorShift::new(1234));
+            }
+            let draw = |chip8: &mut Chip8| {
+                chip8.eval_opcode(0xC0FF).unwrap();
+                chip8.v[0]
+            };
+
+            draw(&mut chip8);
+            let state = chip8.save_state();
+            let numbers: Vec<u8> = (0..8).map(|_| draw(&mut chip8)).collect();
+
+            chip8.load_state(&state).unwrap();
+            assert_eq!((0..8).map(|_| draw(&mut chip8)).collect::<Vec<u8>>(), numbers);
+        }
+    }
+
+    #[test]
+    fn test_version_2() {
+        let chip8 = running_vm();
+        let mut state = chip8.save_state();
+        state[4] = 2;
+        state.truncate(state.len() - 4);
+
+        let mut restored = Chip8::new();
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.keypad_pressed, Some(3));
+        assert_eq!(restored.memory, chip8.memory);
+    }
+
+    #[test]
+    fn test_version_1() {
+        let chip8 = running_vm();
+        let mut state = chip8.save_state();
+        state[4] = 1;
+        state.truncate(state.len() - 5);
+
+        let mut restored = Chip8::new();
+        restored.load_state(&state).unwrap();
+
+        assert!(restored.keypad_waiting);
+        assert_eq!(restored.keypad_pressed, None);
+        assert_eq!(restored.memory, chip8.memory);
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let mut state = Chip8::new().save_state();
+        state[0] = b'X';
+
+        assert_eq!(Chip8::new().load_state(&state), Err(StateError::BadMagic));
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut state = Chip8::new().save_state();
+        state[4] = 0xFF;
+
+        assert_eq!(Chip8::new().load_state(&state), Err(StateError::UnsupportedVersion(0xFF)));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let state = running_vm().save_state();
+        let mut chip8 = Chip8::new();
+
+        assert_eq!(chip8.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+        assert_eq!(chip8.pc, 0x200);
+    }
+
+    #[test]
+    fn test_memory_size_mismatch() {
+        let state = Chip8::with_quirks(Quirks::XO_CHIP).save_state();
+
+        assert_eq!(Chip8::new().load_state(&state),
+                   Err(StateError::MemorySizeMismatch { expected: 4096, found: 65536 }));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut state = Chip8::new().save_state();
+        // Stack pointer
+        state[28] = 17;
+
+        assert_eq!(Chip8::new().load_state(&state), Err(StateError::Invalid));
+    }
+
+    #[test]
+    fn test_invalid_pixel() {
+        let mut state = running_vm().save_state();
+        // The first pixel, which can only be lit on the two bitplanes
+        state[104] = 4;
+
+        assert_eq!(Chip8::new().load_state(&state), Err(StateError::Invalid));
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let mut state = running_vm().save_state();
+        state.push(0);
+
+        assert_eq!(Chip8::new().load_state(&state), Err(StateError::TrailingData));
+    }
+}
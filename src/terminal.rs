@@ -0,0 +1,268 @@
+// The terminal frontend: the screen drawn with half block characters, two
+// CHIP-8 pixels per character, and the keypad read from raw mode stdin.
+//
+// Terminals send characters rather than scancodes, so unlike `Keyboard` the
+// layout follows the letters on the keys, and keys of the keymap that don't
+// type a character or move the cursor are left out. Most terminals don't
+// report key releases either, so a key counts as held for a few frames after
+// each press, and the terminal's key repeat keeps it held. Terminals with the
+// kitty keyboard protocol report releases, and keys are held until they're
+// released.
+
+use std::io::{self, Stdout, Write};
+use std::time::Duration;
+
+use crossterm::event::{
+    self, Event, KeyCode, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::{Color, Colors, Print, SetColors};
+use crossterm::{cursor, queue, terminal};
+
+use chip_8::{AudioSink, Control, FrontendError, InputSource, Screen, VideoSink};
+
+use crate::cli::Rgb;
+use crate::keymap::Keymap;
+
+// A little longer than the interval between two repeats of a held key, so
+// repeats keep it held while a tap is let go almost at once
+const LATCH_FRAMES: u8 = 4;
+// A key held until the terminal reports its release
+const HELD: u8 = u8::MAX;
+
+const UPPER_HALF: char = '▀';
+
+// Keeps the terminal in raw mode on the alternate screen, and restores it
+// when dropped
+pub struct Terminal {
+    releases: bool,
+}
+
+pub struct TerminalVideo {
+    stdout: Stdout,
+    // The size of the terminal, to redraw the screen when it's resized
+    size: (u16, u16),
+    // Indexed by the bitplanes a pixel is lit on
+    palette: [Color; 4],
+}
+
+pub struct TerminalAudio {
+    stdout: Stdout,
+    beeping: bool,
+}
+
+pub struct TerminalInput {
+    // The host keys of each keypad key, with characters in lower case
+    codes: Vec<Vec<KeyCode>>,
+    pause: Vec<KeyCode>,
+    rewind_codes: Vec<KeyCode>,
+    // Frames left before each key counts as released, or HELD
+    keys: [u8; 16],
+    rewind: u8,
+    releases: bool,
+}
+
+impl Terminal {
+    pub fn new() -> io::Result<Terminal> {
+        let mut stdout = io::stdout();
+
+        terminal::enable_raw_mode()?;
+        // From here on, dropping it on an error restores the terminal
+        let mut guard = Terminal { releases: false };
+        queue!(stdout, terminal::EnterAlternateScreen, cursor::Hide, terminal::Clear(terminal::ClearType::All))?;
+
+        if terminal::supports_keyboard_enhancement().unwrap_or(false) {
+            queue!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+            guard.releases = true;
+        }
+        stdout.flush()?;
+
+        Ok(guard)
+    }
+
+    pub fn video(&self, palette: [Rgb; 4]) -> TerminalVideo {
+        let mut colors = [Color::Reset; 4];
+        for (color, &(r, g, b)) in colors.iter_mut().zip(palette.iter()) {
+            *color = Color::Rgb { r, g, b };
+        }
+
+        TerminalVideo { stdout: io::stdout(), size: (0, 0), palette: colors }
+    }
+
+    pub fn audio(&self) -> TerminalAudio {
+        TerminalAudio { stdout: io::stdout(), beeping: false }
+    }
+
+    pub fn input(&self, keymap: &Keymap) -> TerminalInput {
+        let codes = |names: &[String]| names.iter().filter_map(|name| key_code(name)).collect();
+
+        TerminalInput {
+            codes: keymap.keys().map(codes).collect(),
+            pause: codes(keymap.pause()),
+            rewind_codes: codes(keymap.rewind()),
+            keys: [0; 16],
+            rewind: 0,
+            releases: self.releases,
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+
+        if self.releases {
+            let _ = queue!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = queue!(stdout, cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = stdout.flush();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+impl InputSource for TerminalInput {
+    // Escape and Ctrl-C quit, the pause keys pause and the rewind keys rewind
+    fn poll(&mut self) -> Result<Control, FrontendError> {
+        let mut pause = false;
+
+        for held in self.keys.iter_mut().chain(Some(&mut self.rewind)) {
+            if *held != HELD && *held > 0 {
+                *held -= 1;
+            }
+        }
+
+        while event::poll(Duration::from_secs(0))? {
+            let key = match event::read()? {
+                Event::Key(key) => key,
+                _ => continue,
+            };
+
+            let quit = key.code == KeyCode::Esc
+                || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL));
+            if quit {
+                return Ok(Control::Quit);
+            }
+
+            let code = match key.code {
+                KeyCode::Char(c) => KeyCode::Char(lower_case(c)),
+                code => code,
+            };
+            if self.pause.contains(&code) {
+                pause |= key.kind == KeyEventKind::Press;
+                continue;
+            }
+
+            let held = match key.kind {
+                KeyEventKind::Release => 0,
+                _ if self.releases => HELD,
+                _ => LATCH_FRAMES,
+            };
+            if self.rewind_codes.contains(&code) {
+                self.rewind = held;
+                continue;
+            }
+            for (keys, codes) in self.keys.iter_mut().zip(self.codes.iter()) {
+                if codes.contains(&code) {
+                    *keys = held;
+                }
+            }
+        }
+
+        if pause {
+            Ok(Control::Pause)
+        } else if self.rewind > 0 {
+            Ok(Control::Rewind)
+        } else {
+            Ok(Control::Play)
+        }
+    }
+
+    fn keypad(&self) -> [bool; 16] {
+        let mut keypad = [false; 16];
+        for (pressed, &held) in keypad.iter_mut().zip(self.keys.iter()) {
+            *pressed = held > 0;
+        }
+        keypad
+    }
+}
+
+impl VideoSink for TerminalVideo {
+    fn present(&mut self, screen: &Screen) -> Result<(), FrontendError> {
+        let size = terminal::size()?;
+        if size != self.size {
+            queue!(self.stdout, terminal::Clear(terminal::ClearType::All))?;
+            self.size = size;
+        }
+
+        let rows: Vec<&[u8]> = screen.rows().collect();
+        for (line, pair) in rows.chunks(2).enumerate() {
+            queue!(self.stdout, cursor::MoveTo(0, line as u16))?;
+            for x in 0..screen.width() {
+                let top = pair[0][x] & 0b11;
+                let bottom = pair.get(1).map_or(0, |row| row[x] & 0b11);
+                queue!(
+                    self.stdout,
+                    SetColors(Colors::new(self.palette[top as usize], self.palette[bottom as usize])),
+                    Print(UPPER_HALF),
+                )?;
+            }
+        }
+        queue!(self.stdout, SetColors(Colors::new(Color::Reset, Color::Reset)))?;
+        self.stdout.flush()?;
+
+        Ok(())
+    }
+
+    // Resizing the terminal can wipe it
+    fn present_unchanged(&mut self, screen: &Screen) -> Result<(), FrontendError> {
+        if terminal::size()? != self.size {
+            self.present(screen)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AudioSink for TerminalAudio {
+    // Rings the bell when a sound starts, a bell can't be held for as long
+    // as the timer runs
+    fn play(&mut self, sound_timer: u8, _pattern: Option<[u8; 16]>, _pitch: u8) -> Result<(), FrontendError> {
+        let beeping = sound_timer > 0;
+        if beeping && !self.beeping {
+            queue!(self.stdout, Print('\u{7}'))?;
+            self.stdout.flush()?;
+        }
+        self.beeping = beeping;
+
+        Ok(())
+    }
+}
+
+// What the terminal reads for an SDL key name. The numeric keypad types the
+// same characters as the rest of the keyboard.
+fn key_code(name: &str) -> Option<KeyCode> {
+    let name = name.strip_prefix("Keypad ").unwrap_or(name);
+
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => return Some(KeyCode::Char(lower_case(c))),
+        (None, _) => return None,
+        _ => {},
+    }
+
+    match name {
+        "Up" => Some(KeyCode::Up),
+        "Down" => Some(KeyCode::Down),
+        "Left" => Some(KeyCode::Left),
+        "Right" => Some(KeyCode::Right),
+        "Return" | "Enter" => Some(KeyCode::Enter),
+        "Tab" => Some(KeyCode::Tab),
+        "Space" => Some(KeyCode::Char(' ')),
+        "Backspace" => Some(KeyCode::Backspace),
+        _ => None,
+    }
+}
+
+fn lower_case(c: char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
@@ -0,0 +1,155 @@
+// An execution trace, one line per instruction, meant to be diffed against
+// the traces of other emulators. Every line has the same layout:
+//
+//     000000001 0200 6012 LD V0, 0x12          V 12 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 I 0000
+//
+// The instruction count, the address and the opcode, the disassembly padded
+// to 20 characters, then V0 to VF and I after the instruction ran. Numbers are
+// upper case hexadecimal, except for the count which is decimal.
+
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use crate::{Chip8, Chip8Error, Instruction, StepOutcome, OPCODE_SIZE};
+
+pub struct Tracer<W> {
+    output: W,
+    // Only instructions at these addresses are written out
+    range: Option<RangeInclusive<usize>>,
+    // Instructions run so far, whether they were written out or not
+    count: u64,
+    // The first write that failed, nothing else gets written after it
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(output: W) -> Tracer<W> {
+        Tracer { output, range: None, count: 0, error: None }
+    }
+
+    pub fn with_range(output: W, range: RangeInclusive<usize>) -> Tracer<W> {
+        Tracer { output, range: Some(range), count: 0, error: None }
+    }
+
+    // Runs a single cycle, writing out the instruction it ran. Waiting for a
+    // key isn't an instruction and leaves no line.
+    pub fn cycle(&mut self, chip8: &mut Chip8) -> Result<StepOutcome, Chip8Error> {
+        if chip8.keypad_waiting || chip8.exited {
+            return chip8.cycle();
+        }
+
+        let pc = chip8.pc;
+        let opcode = chip8.read_opcode()?;
+        let disassembly = match Instruction::decode(opcode) {
+            Some(Instruction::LoadLongI) => match chip8.opcode_at(pc + OPCODE_SIZE) {
+                Ok(addr) => format!("LD I, long 0x{:04X}", addr),
+                Err(_) => Instruction::LoadLongI.to_string(),
+            },
+            Some(instruction) => instruction.to_string(),
+            None => String::new(),
+        };
+
+        let outcome = chip8.cycle()?;
+        self.count += 1;
+
+        if self.error.is_none() && self.range.as_ref().is_none_or(|range| range.contains(&pc)) {
+            if let Err(error) = self.write(chip8, pc, opcode, &disassembly) {
+                self.error = Some(error);
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    // `Chip8::run_frame`, traced
+    pub fn run_frame(&mut self, chip8: &mut Chip8, instructions_per_frame: usize) -> Result<StepOutcome, Chip8Error> {
+        let mut outcome = StepOutcome::Running;
+
+        for _ in 0..instructions_per_frame {
+            outcome = self.cycle(chip8)?;
+            if outcome != StepOutcome::Running {
+                break;
+            }
+        }
+
+        chip8.tick_timers();
+
+        Ok(outcome)
+    }
+
+    // Flushes the trace, and returns the first error writing it
+    pub fn finish(mut self) -> io::Result<W> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.output.flush().map(|_| self.output),
+        }
+    }
+
+    fn write(&mut self, chip8: &Chip8, pc: usize, opcode: u16, disassembly: &str) -> io::Result<()> {
+        write!(self.output, "{:09} {:04X} {:04X} {:<20} V", self.count, pc, opcode, disassembly)?;
+        for v in chip8.v.iter() {
+            write!(self.output, " {:02X}", v)?;
+        }
+        writeln!(self.output, " I {:04X}", chip8.i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble;
+
+    fn chip8(source: &str) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load(&assemble(source).unwrap().rom);
+        chip8
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut chip8 = chip8("
+            LD V0, 0x12
+            LD I, long 0x0ABC
+            ADD VF, 0xFF
+            LD V1, K
+        ");
+        let mut tracer = Tracer::new(Vec::new());
+
+        for _ in 0..6 {
+            tracer.cycle(&mut chip8).unwrap();
+        }
+
+        let trace = String::from_utf8(tracer.finish().unwrap()).unwrap();
+        assert_eq!(trace.lines().collect::<Vec<_>>(), vec![
+            "000000001 0200 6012 LD V0, 0x12          V 12 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 I 0000",
+            "000000002 0202 F000 LD I, long 0x0ABC    V 12 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 I 0ABC",
+            "000000003 0206 7FFF ADD VF, 0xFF         V 12 00 00 00 00 00 00 00 00 00 00 00 00 00 00 FF I 0ABC",
+            "000000004 0208 F10A LD V1, K             V 12 00 00 00 00 00 00 00 00 00 00 00 00 00 00 FF I 0ABC",
+        ]);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut chip8 = chip8("
+            LD V0, 1
+            loop: ADD V0, 1
+            JP loop
+        ");
+        let mut tracer = Tracer::with_range(Vec::new(), 0x202..=0x202);
+
+        tracer.run_frame(&mut chip8, 5).unwrap();
+
+        let trace = String::from_utf8(tracer.finish().unwrap()).unwrap();
+        let lines: Vec<&str> = trace.lines().map(|line| &line[..19]).collect();
+        assert_eq!(lines, vec!["000000002 0202 7001", "000000004 0202 7001"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut chip8 = chip8("RET");
+        let mut tracer = Tracer::new(Vec::new());
+
+        assert_eq!(tracer.cycle(&mut chip8), Err(Chip8Error::StackUnderflow));
+        assert!(tracer.finish().unwrap().is_empty());
+    }
+}